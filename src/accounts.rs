@@ -0,0 +1,106 @@
+// Copyright 2024 David Araújo
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::init;
+
+/// The uid and primary gid of `user`, a name or a uid, as listed in the
+/// `/etc/passwd` of `rootfs`. A uid without an entry gets gid 0.
+pub fn user(rootfs: &Path, user: &str) -> Result<(u32, u32)> {
+    let uid = user.parse::<u32>().ok();
+    let entry = entries(rootfs, "/etc/passwd")?
+        .into_iter()
+        .find(|fields| fields[0] == user || (uid.is_some() && fields[2].parse().ok() == uid));
+
+    match (entry, uid) {
+        (Some(fields), _) => Ok((number(&fields, 2)?, number(&fields, 3)?)),
+        (None, Some(uid)) => Ok((uid, 0)),
+        (None, None) => Err(Error::Invalid(format!(
+            "user `{}` is not in the container's /etc/passwd",
+            user
+        ))),
+    }
+}
+
+/// The gid of `group`, a name or a gid, as listed in the `/etc/group`
+/// of `rootfs`.
+pub fn group(rootfs: &Path, group: &str) -> Result<u32> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(gid);
+    }
+    match entries(rootfs, "/etc/group")?
+        .into_iter()
+        .find(|fields| fields[0] == group)
+    {
+        Some(fields) => number(&fields, 2),
+        None => Err(Error::Invalid(format!(
+            "group `{}` is not in the container's /etc/group",
+            group
+        ))),
+    }
+}
+
+// The `:` separated fields of each line with at least four of them
+fn entries(rootfs: &Path, file: &str) -> Result<Vec<Vec<String>>> {
+    let path = init::resolve(rootfs, file);
+    let contents = std::fs::read_to_string(&path).map_err(Error::io(&path))?;
+    Ok(contents
+        .lines()
+        .map(|line| line.split(':').map(str::to_string).collect::<Vec<_>>())
+        .filter(|fields| fields.len() >= 4)
+        .collect())
+}
+
+fn number(fields: &[String], index: usize) -> Result<u32> {
+    fields[index].parse().map_err(|_| {
+        Error::Invalid(format!(
+            "`{}` of `{}` is not a number",
+            fields[index], fields[0]
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_and_ids_resolve_against_the_rootfs() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tmp.path().join("etc")).unwrap();
+        std::fs::write(
+            tmp.path().join("etc/passwd"),
+            "root:x:0:0:root:/root:/bin/sh\n\
+             app:x:1000:1000:App:/home/app:/bin/sh\n",
+        )
+        .unwrap();
+        std::fs::write(
+            tmp.path().join("etc/group"),
+            "root:x:0:root\nwww-data:x:82:app\n",
+        )
+        .unwrap();
+
+        assert_eq!(user(tmp.path(), "app").unwrap(), (1000, 1000));
+        assert_eq!(user(tmp.path(), "1000").unwrap(), (1000, 1000));
+        assert_eq!(user(tmp.path(), "4242").unwrap(), (4242, 0));
+        assert_eq!(group(tmp.path(), "www-data").unwrap(), 82);
+        assert_eq!(group(tmp.path(), "7").unwrap(), 7);
+        assert_eq!(
+            user(tmp.path(), "nobody").unwrap_err().to_string(),
+            "user `nobody` is not in the container's /etc/passwd"
+        );
+    }
+}
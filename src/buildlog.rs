@@ -0,0 +1,219 @@
+// Copyright 2024 David Araújo
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::context::Context;
+use crate::error::{Error, Result};
+use crate::inspect::{self, Row};
+use crate::output::Output;
+
+/// How a build step ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Done,
+    /// Skipped, the build cache has a snapshot taken after it
+    Cached,
+    Failed,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::Done => "done",
+            Outcome::Cached => "cached",
+            Outcome::Failed => "failed",
+        })
+    }
+}
+
+/// A build step as it ran.
+#[derive(Debug)]
+pub struct Record {
+    /// Its place in the LXCfile, such as `run[2]`, or what it does, such
+    /// as `restart`
+    pub step: String,
+    pub time: Duration,
+    pub outcome: Outcome,
+    /// What its commands printed, if the transcript was kept
+    pub output: Vec<u8>,
+}
+
+/// Timed records of the steps of a build, in the order they ran.
+#[derive(Debug)]
+pub struct BuildLog {
+    started: Instant,
+    pub records: Vec<Record>,
+}
+
+impl BuildLog {
+    pub fn new() -> BuildLog {
+        BuildLog {
+            started: Instant::now(),
+            records: Vec::new(),
+        }
+    }
+
+    /// Runs `f` as the build step `step` and records how it went. Its
+    /// errors name the step.
+    pub fn step<T>(
+        &mut self,
+        out: &Output,
+        step: impl Into<String>,
+        f: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        let step = step.into();
+        // Whatever ran in between is no part of this step
+        out.take_transcript();
+        let started = Instant::now();
+        let result = f();
+        self.records.push(Record {
+            step: step.clone(),
+            time: started.elapsed(),
+            outcome: if result.is_ok() {
+                Outcome::Done
+            } else {
+                Outcome::Failed
+            },
+            output: out.take_transcript(),
+        });
+        result.map_err(Error::step(step))
+    }
+
+    pub fn cached(&mut self, step: impl Into<String>) {
+        self.records.push(Record {
+            step: step.into(),
+            time: Duration::ZERO,
+            outcome: Outcome::Cached,
+            output: Vec::new(),
+        });
+    }
+
+    /// Every step with its time and outcome, then the time of the whole
+    /// build.
+    pub fn summary(&self) -> String {
+        let rows: Vec<SummaryRow> = self
+            .records
+            .iter()
+            .map(|record| SummaryRow {
+                step: record.step.clone(),
+                time: seconds(record.time),
+                outcome: record.outcome.to_string(),
+            })
+            .chain(std::iter::once(SummaryRow {
+                step: "total".to_string(),
+                time: seconds(self.started.elapsed()),
+                outcome: String::new(),
+            }))
+            .collect();
+        inspect::table(&rows)
+    }
+
+    /// Writes every step to `path` with what its commands printed.
+    pub fn write(&self, ctx: &Context, path: &Path) -> Result<()> {
+        let mut contents = String::new();
+        for record in &self.records {
+            contents.push_str(&format!(
+                "==> {}: {} in {}\n",
+                record.step,
+                record.outcome,
+                seconds(record.time)
+            ));
+            contents.push_str(&String::from_utf8_lossy(&record.output));
+            if !contents.ends_with('\n') {
+                contents.push('\n');
+            }
+        }
+        contents.push_str(&format!("==> total: {}\n", seconds(self.started.elapsed())));
+        ctx.write_file(path, &contents, 0o644)
+    }
+}
+
+struct SummaryRow {
+    step: String,
+    time: String,
+    outcome: String,
+}
+
+impl Row for SummaryRow {
+    fn headers() -> &'static [&'static str] {
+        &["STEP", "TIME", "RESULT"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![self.step.clone(), self.time.clone(), self.outcome.clone()]
+    }
+}
+
+fn seconds(time: Duration) -> String {
+    format!("{:.1}s", time.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::Cmd;
+    use crate::output::{ColorChoice, Verbosity};
+
+    #[test]
+    fn steps_are_timed_with_what_they_printed() {
+        let out = Output::new(Verbosity::Quiet, ColorChoice::Never);
+        out.keep_transcript();
+        let mut log = BuildLog::new();
+
+        let sh = |script: &str| Cmd::new("sh").arg("-c").arg(script);
+        log.step(&out, "run[1]", || sh("echo out; echo err >&2").run(&out))
+            .unwrap();
+        log.cached("run[2]");
+        let err = log
+            .step(&out, "run[3]", || sh("echo failing; exit 3").run(&out))
+            .unwrap_err();
+        assert_eq!(err.exit_code(), 3);
+        assert!(err.to_string().starts_with("build step run[3] failed: "));
+
+        let outcomes: Vec<(&str, Outcome)> = log
+            .records
+            .iter()
+            .map(|record| (record.step.as_str(), record.outcome))
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                ("run[1]", Outcome::Done),
+                ("run[2]", Outcome::Cached),
+                ("run[3]", Outcome::Failed)
+            ]
+        );
+        let summary = log.summary();
+        assert!(summary.starts_with("STEP    TIME  RESULT\nrun[1]  "));
+        assert!(summary.lines().last().unwrap().starts_with("total   "));
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("build.log");
+        log.write(&Context::default(), &path).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert!(lines[0].starts_with("==> run[1]: done in "));
+        // Both streams, in whichever order they arrived
+        let mut printed = lines[1..3].to_vec();
+        printed.sort();
+        assert_eq!(printed, ["err", "out"]);
+        assert_eq!(lines[3], "==> run[2]: cached in 0.0s");
+        assert!(lines[4].starts_with("==> run[3]: failed in "));
+        assert_eq!(lines[5], "failing");
+        assert!(lines[6].starts_with("==> total: "));
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright 2024 David Araújo
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result};
+
+/// File in the cache container's directory listing its snapshots.
+const INDEX: &str = "cmt-cache";
+
+/// Key of a build step, which depends on every step before it.
+pub fn key(previous: &str, step: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(previous);
+    hasher.update("\n");
+    hasher.update(step);
+    hex(&hasher.finalize())
+}
+
+/// Checksum of a file, or of every file under a directory with its
+/// relative path. Symbolic links are hashed by the path they point to,
+/// unless `follow` says to hash what they point to instead, as copies
+/// that follow links do.
+pub fn checksum(path: &Path, follow: bool) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hash_path(&mut hasher, path, Path::new(""), follow)?;
+    Ok(hex(&hasher.finalize()))
+}
+
+fn hash_path(hasher: &mut Sha256, path: &Path, relative: &Path, follow: bool) -> Result<()> {
+    let metadata = if follow {
+        std::fs::metadata(path)
+    } else {
+        std::fs::symlink_metadata(path)
+    };
+    let metadata = metadata.map_err(Error::io(path))?;
+    hasher.update(relative.as_os_str().as_encoded_bytes());
+    hasher.update([0]);
+    if metadata.is_symlink() {
+        let target = std::fs::read_link(path).map_err(Error::io(path))?;
+        hasher.update(b"link\0");
+        hasher.update(target.as_os_str().as_encoded_bytes());
+    } else if metadata.is_dir() {
+        hasher.update(b"dir\0");
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(path).map_err(Error::io(path))? {
+            entries.push(entry.map_err(Error::io(path))?.file_name());
+        }
+        entries.sort();
+        for entry in entries {
+            hash_path(hasher, &path.join(&entry), &relative.join(&entry), follow)?;
+        }
+    } else {
+        hasher.update(b"file\0");
+        hasher.update(std::fs::read(path).map_err(Error::io(path))?);
+    }
+    hasher.update([0]);
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Snapshots of a cache container, one per build step taken so far, with
+/// the key of the step each one was taken after.
+#[derive(Debug, Default)]
+pub struct Index {
+    path: PathBuf,
+    /// `(key, snapshot)` pairs in build order
+    pub entries: Vec<(String, String)>,
+}
+
+impl Index {
+    /// Reads the index kept in `dir`, empty when there is none.
+    pub fn load(dir: &Path) -> Result<Index> {
+        let path = dir.join(INDEX);
+        let entries = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents
+                .lines()
+                .filter_map(|line| line.split_once(' '))
+                .map(|(key, snapshot)| (key.to_string(), snapshot.to_string()))
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(Error::io(&path)(e)),
+        };
+        Ok(Index { path, entries })
+    }
+
+    /// How many of `keys` have a snapshot, counting from the first.
+    pub fn matching(&self, keys: &[String]) -> usize {
+        self.entries
+            .iter()
+            .zip(keys)
+            .take_while(|((cached, _), key)| cached == *key)
+            .count()
+    }
+
+    pub fn push(&mut self, key: String, snapshot: String) -> Result<()> {
+        self.entries.push((key, snapshot));
+        self.save()
+    }
+
+    /// Forgets every snapshot after the first `len`, returning them.
+    pub fn truncate(&mut self, len: usize) -> Result<Vec<String>> {
+        let stale = self.entries.split_off(len.min(self.entries.len()));
+        self.save()?;
+        Ok(stale.into_iter().map(|(_, snapshot)| snapshot).collect())
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let contents: String = self
+            .entries
+            .iter()
+            .map(|(key, snapshot)| format!("{} {}\n", key, snapshot))
+            .collect();
+        std::fs::write(&self.path, contents).map_err(Error::io(&self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums_follow_contents_and_names() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("app");
+        std::fs::create_dir_all(dir.join("conf")).unwrap();
+        std::fs::write(dir.join("conf/app.conf"), "port = 80\n").unwrap();
+        let before = checksum(&dir, false).unwrap();
+        assert_eq!(checksum(&dir, false).unwrap(), before);
+
+        std::fs::write(dir.join("conf/app.conf"), "port = 81\n").unwrap();
+        let changed = checksum(&dir, false).unwrap();
+        assert_ne!(changed, before);
+
+        std::fs::rename(dir.join("conf/app.conf"), dir.join("conf/web.conf")).unwrap();
+        assert_ne!(checksum(&dir, false).unwrap(), changed);
+
+        // A link is its target only when links are followed
+        let target = tmp.path().join("target.conf");
+        std::fs::write(&target, "port = 80\n").unwrap();
+        std::os::unix::fs::symlink(&target, dir.join("link.conf")).unwrap();
+        let (linked, followed) = (
+            checksum(&dir, false).unwrap(),
+            checksum(&dir, true).unwrap(),
+        );
+        std::fs::write(&target, "port = 81\n").unwrap();
+        assert_eq!(checksum(&dir, false).unwrap(), linked);
+        assert_ne!(checksum(&dir, true).unwrap(), followed);
+
+        assert_ne!(key("", "run"), key(&key("", "create"), "run"));
+    }
+}
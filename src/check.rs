@@ -0,0 +1,618 @@
+// Copyright 2024 David Araújo
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+use std::iter;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+
+use crate::lxcfile::{Copy, Image, LxcFile, Network, NetworkType, Restart, Run};
+
+/// Controllers a `[limits]` key may start with, cgroup v1 and v2.
+const CONTROLLERS: [&str; 13] = [
+    "blkio",
+    "cpu",
+    "cpuacct",
+    "cpuset",
+    "devices",
+    "freezer",
+    "hugetlb",
+    "io",
+    "memory",
+    "misc",
+    "perf_event",
+    "pids",
+    "rdma",
+];
+
+/// Something wrong with an LXCfile, found without touching the host.
+#[derive(Debug, PartialEq)]
+pub struct Problem {
+    /// Where in the file, such as `copy[2].host`
+    pub field: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+/// Every problem in `file`, whose relative paths are read from the build
+/// `context` directory.
+pub fn check(file: &LxcFile, context: &Path) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut problem = |field: String, message: String| problems.push(Problem { field, message });
+
+    if let Err(message) = container_name(&file.name) {
+        problem("name".to_string(), message);
+    }
+
+    if file.replicas == Some(0) {
+        problem(
+            "replicas".to_string(),
+            "there must be at least one replica".to_string(),
+        );
+    }
+
+    if file.restart == Restart::Always && file.entrypoint.is_none() {
+        problem(
+            "restart".to_string(),
+            "there is no entrypoint to restart".to_string(),
+        );
+    }
+
+    for (key, value) in &file.env {
+        let valid = key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            problem(
+                format!("env.{}", key),
+                format!("`{}` is not a valid variable name", key),
+            );
+        }
+        // Each one is a line of the container's configuration
+        if value.contains('\n') {
+            problem(format!("env.{}", key), "contains a newline".to_string());
+        }
+    }
+
+    if let Some(workdir) = &file.workdir {
+        if !workdir.starts_with('/') {
+            problem(
+                "workdir".to_string(),
+                format!("`{}` is not an absolute path", workdir),
+            );
+        }
+    }
+
+    let mut stages: Vec<&str> = Vec::new();
+    for (i, stage) in file.stage.iter().enumerate() {
+        let prefix = format!("stage[{}].", i + 1);
+        if stages.contains(&stage.name.as_str()) {
+            problem(
+                format!("{}name", prefix),
+                format!("`{}` is already the name of a stage", stage.name),
+            );
+        } else if let Err(message) = container_name(&file.stage_container(stage)) {
+            // The stage's container is named after it
+            problem(format!("{}name", prefix), message);
+        }
+        image_problems(&mut problem, &prefix, &stage.image);
+        copy_problems(
+            &mut problem,
+            &prefix,
+            &stage.copy,
+            &stages,
+            context,
+            &file.inherited,
+        );
+        run_problems(&mut problem, &prefix, &stage.run, None);
+        stages.push(&stage.name);
+    }
+
+    image_problems(&mut problem, "", &file.image);
+    copy_problems(
+        &mut problem,
+        "",
+        &file.copy,
+        &stages,
+        context,
+        &file.inherited,
+    );
+    run_problems(&mut problem, "", &file.run, file.workdir.as_deref());
+
+    for (i, template) in file.template.iter().enumerate() {
+        let field = format!("template[{}]", i + 1);
+        if let Err(message) = in_context(context, &file.inherited, &template.source) {
+            problem(format!("{}.source", field), message);
+        }
+        if !template.container.starts_with('/') {
+            problem(
+                format!("{}.container", field),
+                format!("`{}` is not an absolute path", template.container),
+            );
+        }
+        if let Err(message) = template.mode() {
+            problem(format!("{}.mode", field), message);
+        }
+    }
+
+    for (i, network) in file.network.iter().enumerate() {
+        network_problems(&mut problem, &format!("network[{}]", i + 1), network);
+    }
+    // Replicas are copies of the first one, addresses included
+    if let Some(replicas @ 2..) = file.replicas {
+        for (i, network) in file.network.iter().enumerate() {
+            for (key, value) in [
+                ("hwaddr", &network.hwaddr),
+                ("ipv4", &network.ipv4),
+                ("ipv6", &network.ipv6),
+            ] {
+                if value.is_some() {
+                    problem(
+                        format!("network[{}].{}", i + 1, key),
+                        format!("a static address cannot be shared by {} replicas", replicas),
+                    );
+                }
+            }
+        }
+    }
+
+    for (i, shared) in file.shared.iter().enumerate() {
+        for (field, path) in [("host", &shared.host), ("container", &shared.container)] {
+            let field = format!("shared[{}].{}", i + 1, field);
+            if path.trim().is_empty() {
+                problem(field, "is empty".to_string());
+            } else if path.contains(char::is_whitespace) {
+                // lxc.mount.entry is split on whitespace like fstab
+                problem(field, format!("`{}` contains whitespace", path));
+            }
+        }
+        if shared.host.starts_with('~') {
+            problem(
+                format!("shared[{}].host", i + 1),
+                format!("`{}` is not expanded, use an absolute path", shared.host),
+            );
+        }
+    }
+
+    for (i, device) in file.device.iter().enumerate() {
+        let field = format!("device[{}]", i + 1);
+        if !device.path.starts_with("/dev/") {
+            problem(
+                format!("{}.path", field),
+                format!("`{}` is not a path under /dev", device.path),
+            );
+        }
+        let access = device.access();
+        if access.is_empty() || !access.chars().all(|c| "rwm".contains(c)) {
+            problem(
+                format!("{}.access", field),
+                format!("`{}` is not made of `r`, `w` and `m`", access),
+            );
+        }
+    }
+
+    for key in file.limits.keys() {
+        let known = CONTROLLERS
+            .iter()
+            .any(|controller| key.starts_with(&format!("{}_", controller)));
+        if !known {
+            problem(
+                format!("limits.{}", key),
+                format!(
+                    "`{}` is not a cgroup controller item, expected one of {}",
+                    key.replace('_', "."),
+                    CONTROLLERS.join(", ")
+                ),
+            );
+        }
+    }
+
+    problems
+}
+
+fn image_problems(problem: &mut impl FnMut(String, String), prefix: &str, image: &Image) {
+    for (field, value) in [
+        ("distro", &image.distro),
+        ("release", &image.release),
+        ("arch", &image.arch),
+    ] {
+        if let Err(message) = image_part(value) {
+            problem(format!("{}image.{}", prefix, field), message);
+        }
+    }
+}
+
+// Host paths must stay inside the build context, and stage paths can
+// only come from a stage built earlier
+fn copy_problems(
+    problem: &mut impl FnMut(String, String),
+    prefix: &str,
+    copies: &[Copy],
+    stages: &[&str],
+    context: &Path,
+    inherited: &[PathBuf],
+) {
+    for (i, copy) in copies.iter().enumerate() {
+        let field = format!("{}copy[{}]", prefix, i + 1);
+        if let Some(from) = &copy.from {
+            if !stages.contains(&from.as_str()) {
+                problem(
+                    format!("{}.from", field),
+                    format!("`{}` is not a stage built before this one", from),
+                );
+            }
+            if !copy.host.starts_with('/') {
+                problem(
+                    format!("{}.host", field),
+                    format!("`{}` is not an absolute path in stage {}", copy.host, from),
+                );
+            }
+            continue;
+        }
+
+        if let Err(message) = in_context(context, inherited, &copy.host) {
+            problem(format!("{}.host", field), message);
+        }
+    }
+}
+
+// Inherited paths may also be in the directory of the file they came from
+fn in_context(context: &Path, inherited: &[PathBuf], host: &str) -> Result<(), String> {
+    let Ok(path) = context.join(host).canonicalize() else {
+        return Err(format!("`{}` does not exist in the build context", host));
+    };
+    let mut roots = iter::once(context)
+        .chain(inherited.iter().map(PathBuf::as_path))
+        .filter_map(|root| root.canonicalize().ok());
+    if roots.any(|root| path.starts_with(root)) {
+        Ok(())
+    } else {
+        Err(format!("`{}` is outside the build context", host))
+    }
+}
+
+// A step's workdir taken from the top-level `default` is reported there
+fn run_problems(
+    problem: &mut impl FnMut(String, String),
+    prefix: &str,
+    runs: &[Run],
+    default: Option<&str>,
+) {
+    for (i, run) in runs.iter().enumerate() {
+        match run.workdir.as_deref() {
+            Some(workdir) if !workdir.starts_with('/') && Some(workdir) != default => problem(
+                format!("{}run[{}].workdir", prefix, i + 1),
+                format!("`{}` is not an absolute path", workdir),
+            ),
+            _ => {}
+        }
+    }
+}
+
+fn network_problems(problem: &mut impl FnMut(String, String), field: &str, network: &Network) {
+    let set = [
+        ("link", network.link.is_some()),
+        ("hwaddr", network.hwaddr.is_some()),
+        ("ipv4", network.ipv4.is_some()),
+        ("ipv4_gateway", network.ipv4_gateway.is_some()),
+        ("ipv6", network.ipv6.is_some()),
+        ("ipv6_gateway", network.ipv6_gateway.is_some()),
+        ("mtu", network.mtu.is_some()),
+    ];
+    if network.kind == NetworkType::None {
+        for (key, _) in set.iter().filter(|(_, set)| *set) {
+            problem(
+                format!("{}.{}", field, key),
+                "has no effect with type `none`".to_string(),
+            );
+        }
+        return;
+    }
+    if network.kind == NetworkType::Macvlan && network.link.is_none() {
+        problem(
+            format!("{}.link", field),
+            "is required by a macvlan, as the host interface".to_string(),
+        );
+    }
+
+    if let Some(hwaddr) = &network.hwaddr {
+        // lxc fills in each `x` with a random digit
+        let valid = hwaddr.split(':').count() == 6
+            && hwaddr.split(':').all(|part| {
+                part.len() == 2 && part.chars().all(|c| c.is_ascii_hexdigit() || c == 'x')
+            });
+        if !valid {
+            problem(
+                format!("{}.hwaddr", field),
+                format!("`{}` is not a MAC address", hwaddr),
+            );
+        }
+    }
+    for (key, address, v4) in [
+        ("ipv4", &network.ipv4, true),
+        ("ipv6", &network.ipv6, false),
+    ] {
+        let Some(address) = address else { continue };
+        let max = if v4 { 32 } else { 128 };
+        let valid = match address.split_once('/') {
+            Some((address, prefix)) => {
+                ip(address, v4) && prefix.parse::<u8>().is_ok_and(|prefix| prefix <= max)
+            }
+            None => ip(address, v4),
+        };
+        if !valid {
+            problem(
+                format!("{}.{}", field, key),
+                format!(
+                    "`{}` is not an {} address",
+                    address,
+                    if v4 { "IPv4" } else { "IPv6" }
+                ),
+            );
+        }
+    }
+    for (key, gateway, v4) in [
+        ("ipv4_gateway", &network.ipv4_gateway, true),
+        ("ipv6_gateway", &network.ipv6_gateway, false),
+    ] {
+        let Some(gateway) = gateway else { continue };
+        // `auto` is the address of the bridge, `dev` a route on the link
+        if !ip(gateway, v4) && gateway != "auto" && gateway != "dev" {
+            problem(
+                format!("{}.{}", field, key),
+                format!("`{}` is not an address, `auto` or `dev`", gateway),
+            );
+        }
+    }
+}
+
+fn ip(address: &str, v4: bool) -> bool {
+    if v4 {
+        address.parse::<Ipv4Addr>().is_ok()
+    } else {
+        address.parse::<Ipv6Addr>().is_ok()
+    }
+}
+
+// Same rules as a hostname label, which is what lxc uses the name for
+fn container_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("is empty".to_string());
+    }
+    if name.len() > 64 {
+        return Err(format!("`{}` is longer than 64 characters", name));
+    }
+    if name.starts_with(['-', '.']) {
+        return Err(format!("`{}` starts with `{}`", name, &name[..1]));
+    }
+    match name
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() && !"-_.".contains(*c))
+    {
+        Some(c) => Err(format!("`{}` contains `{}`", name, c)),
+        None => Ok(()),
+    }
+}
+
+// Joined as DISTRO:RELEASE:ARCH and handed to the download template
+fn image_part(value: &str) -> Result<(), String> {
+    if value.is_empty() {
+        return Err("is empty".to_string());
+    }
+    match value
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() && !"-_.".contains(*c))
+    {
+        Some(c) => Err(format!("`{}` contains `{}`", value, c)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_every_problem_at_once() {
+        let tmp = tempfile::tempdir().unwrap();
+        let context = tmp.path().join("context");
+        std::fs::create_dir(&context).unwrap();
+        std::fs::write(context.join("app.conf"), "").unwrap();
+        std::fs::write(tmp.path().join("secret"), "").unwrap();
+
+        let file = LxcFile::parse(
+            Path::new("LXCfile.toml"),
+            r#"
+name = "-web"
+replicas = 0
+
+[image]
+distro = "alpine"
+release = "3.19"
+arch = "amd 64"
+
+[limits]
+cpuset_cpus = "0"
+memory_max = "256M"
+network_speed = "1G"
+
+[[copy]]
+host = "app.conf"
+container = "/etc/app.conf"
+
+[[copy]]
+host = "missing.conf"
+container = "/etc/missing.conf"
+
+[[copy]]
+host = "../secret"
+container = "/secret"
+
+[[shared]]
+host = "/srv/my data"
+container = "mnt/data"
+"#,
+        )
+        .unwrap();
+
+        let problems: Vec<String> = check(&file, &context)
+            .iter()
+            .map(Problem::to_string)
+            .collect();
+        assert_eq!(
+            problems,
+            [
+                "name: `-web` starts with `-`",
+                "replicas: there must be at least one replica",
+                "image.arch: `amd 64` contains ` `",
+                "copy[2].host: `missing.conf` does not exist in the build context",
+                "copy[3].host: `../secret` is outside the build context",
+                "shared[1].host: `/srv/my data` contains whitespace",
+                "limits.network_speed: `network.speed` is not a cgroup controller item, \
+                 expected one of blkio, cpu, cpuacct, cpuset, devices, freezer, hugetlb, io, \
+                 memory, misc, perf_event, pids, rdma",
+            ]
+        );
+    }
+
+    #[test]
+    fn stages_copy_only_from_earlier_stages() {
+        let file = LxcFile::parse(
+            Path::new("LXCfile.toml"),
+            r#"
+name = "app"
+
+[[stage]]
+name = "deps"
+image = { distro = "alpine", release = "3.19", arch = "amd64" }
+copy = [{ from = "build", host = "/out", container = "/out" }]
+
+[[stage]]
+name = "build"
+image = { distro = "alpine", release = "3.19", arch = "amd64" }
+copy = [{ from = "deps", host = "/deps", container = "/deps" }]
+
+[[stage]]
+name = "deps"
+image = { distro = "alpine", release = "3.19", arch = "amd64" }
+
+[image]
+distro = "alpine"
+release = "3.19"
+arch = "amd64"
+
+[[copy]]
+from = "build"
+host = "out/app"
+container = "/usr/bin/app"
+"#,
+        )
+        .unwrap();
+
+        let problems: Vec<String> = check(&file, Path::new("."))
+            .iter()
+            .map(Problem::to_string)
+            .collect();
+        assert_eq!(
+            problems,
+            [
+                "stage[1].copy[1].from: `build` is not a stage built before this one",
+                "stage[3].name: `deps` is already the name of a stage",
+                "copy[1].host: `out/app` is not an absolute path in stage build",
+            ]
+        );
+    }
+
+    #[test]
+    fn network_interfaces_are_checked() {
+        let file = LxcFile::parse(
+            Path::new("LXCfile.toml"),
+            r#"
+name = "web"
+
+[image]
+distro = "alpine"
+release = "3.19"
+arch = "amd64"
+
+[[network]]
+type = "veth"
+ipv4 = "10.0.3.300/24"
+ipv6 = "fd00::10/64"
+ipv6_gateway = "fd00::1"
+
+[[network]]
+type = "macvlan"
+hwaddr = "00:16:3e:12:34"
+ipv4_gateway = "gateway"
+
+[[network]]
+type = "none"
+mtu = 1500
+"#,
+        )
+        .unwrap();
+
+        let problems: Vec<String> = check(&file, Path::new("."))
+            .iter()
+            .map(Problem::to_string)
+            .collect();
+        assert_eq!(
+            problems,
+            [
+                "network[1].ipv4: `10.0.3.300/24` is not an IPv4 address",
+                "network[2].link: is required by a macvlan, as the host interface",
+                "network[2].hwaddr: `00:16:3e:12:34` is not a MAC address",
+                "network[2].ipv4_gateway: `gateway` is not an address, `auto` or `dev`",
+                "network[3].mtu: has no effect with type `none`",
+            ]
+        );
+    }
+
+    #[test]
+    fn replicas_take_no_static_address() {
+        let file = LxcFile::parse(
+            Path::new("LXCfile.toml"),
+            r#"
+name = "web"
+replicas = 3
+
+[image]
+distro = "alpine"
+release = "3.19"
+arch = "amd64"
+
+[[network]]
+type = "veth"
+link = "lxcbr0"
+ipv4 = "10.0.3.10/24"
+ipv4_gateway = "10.0.3.1"
+"#,
+        )
+        .unwrap();
+
+        let problems: Vec<String> = check(&file, Path::new("."))
+            .iter()
+            .map(Problem::to_string)
+            .collect();
+        assert_eq!(
+            problems,
+            ["network[1].ipv4: a static address cannot be shared by 3 replicas"]
+        );
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright 2024 David Araújo
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+use std::io::{Read, Write};
+use std::process::{Command, ExitStatus, Stdio};
+
+use crate::error::{Error, Result};
+use crate::output::{Event, Output, Verbosity};
+
+/// A program and its argument vector. Arguments are never re-split, so
+/// whatever is pushed here reaches the program exactly as written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cmd {
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl Cmd {
+    pub fn new(program: impl Into<String>) -> Cmd {
+        Cmd {
+            program: program.into(),
+            args: Vec::new(),
+        }
+    }
+
+    pub fn arg(mut self, arg: impl Into<String>) -> Cmd {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Cmd
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn to_command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+        command
+    }
+
+    /// Runs the command attached to the terminal, unless in quiet mode,
+    /// and fails unless it exits successfully.
+    pub fn run(&self, out: &Output) -> Result<()> {
+        let status = self.status(out)?;
+        if !status.success() {
+            return Err(self.failed(status));
+        }
+        Ok(())
+    }
+
+    /// Runs the command like `run`, but hands back its exit status
+    /// whatever it is.
+    pub fn status(&self, out: &Output) -> Result<ExitStatus> {
+        out.emit(Event::Command(self));
+        if out.keeps_transcript() {
+            return self.tee(out);
+        }
+        self.to_command()
+            .stdout(stdio(out))
+            .stderr(stdio(out))
+            .status()
+            .map_err(|source| self.spawn_failed(source))
+    }
+
+    /// Runs the command and returns what it printed to stdout.
+    pub fn capture(&self, out: &Output) -> Result<String> {
+        out.emit(Event::Command(self));
+        let output = self
+            .to_command()
+            .stderr(stdio(out))
+            .output()
+            .map_err(|source| self.spawn_failed(source))?;
+        if !output.status.success() {
+            return Err(self.failed(output.status));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    // Shows what the command prints as `status` would, and also adds it
+    // to the transcript, both streams in the order they arrive
+    fn tee(&self, out: &Output) -> Result<ExitStatus> {
+        let mut child = self
+            .to_command()
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|source| self.spawn_failed(source))?;
+        let (stdout, stderr) = (child.stdout.take(), child.stderr.take());
+        std::thread::scope(|scope| {
+            if let Some(stdout) = stdout {
+                scope.spawn(|| copy(stdout, std::io::stdout(), out));
+            }
+            if let Some(stderr) = stderr {
+                scope.spawn(|| copy(stderr, std::io::stderr(), out));
+            }
+        });
+        child.wait().map_err(|source| self.spawn_failed(source))
+    }
+
+    fn spawn_failed(&self, source: std::io::Error) -> Error {
+        Error::Spawn {
+            program: self.program.clone(),
+            source,
+        }
+    }
+
+    fn failed(&self, status: ExitStatus) -> Error {
+        Error::Failed {
+            cmd: self.to_string(),
+            status,
+        }
+    }
+}
+
+// Quiet mode discards whatever the command prints
+fn stdio(out: &Output) -> Stdio {
+    if out.shows(Verbosity::Normal) {
+        Stdio::inherit()
+    } else {
+        Stdio::null()
+    }
+}
+
+// Copies `from` into the transcript until it is closed, and to `to`
+// unless in quiet mode
+fn copy(mut from: impl Read, mut to: impl Write, out: &Output) {
+    let mut buffer = [0; 8192];
+    while let Ok(read) = from.read(&mut buffer) {
+        if read == 0 {
+            break;
+        }
+        if out.shows(Verbosity::Normal) {
+            // The terminal going away is no reason to stop the command
+            let _ = to.write_all(&buffer[..read]).and_then(|()| to.flush());
+        }
+        out.transcribe(&buffer[..read]);
+    }
+}
+
+// Renders the command as it could be pasted into a shell
+impl fmt::Display for Cmd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", quote(&self.program))?;
+        for arg in &self.args {
+            write!(f, " {}", quote(arg))?;
+        }
+        Ok(())
+    }
+}
+
+/// Quotes `word` for a POSIX shell, leaving plain words alone.
+pub fn quote(word: &str) -> String {
+    let plain = !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=,+@%".contains(c));
+    if plain {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{ColorChoice, Output, Verbosity};
+
+    #[test]
+    fn arguments_reach_the_program_as_written() {
+        let cmd = Cmd::new("sh").args([
+            "-c",
+            r#"printf '%s|' "$@""#,
+            "sh",
+            "a b",
+            "it's",
+            "$HOME",
+            "*",
+            "",
+        ]);
+        let out = Output::new(Verbosity::Quiet, ColorChoice::Never);
+        assert_eq!(cmd.capture(&out).unwrap(), "a b|it's|$HOME|*||");
+
+        // Pasted into a shell, it runs the same command
+        assert_eq!(
+            cmd.to_string(),
+            r#"sh -c 'printf '\''%s|'\'' "$@"' sh 'a b' 'it'\''s' '$HOME' '*' ''"#
+        );
+        assert_eq!(quote("--name=web-1"), "--name=web-1");
+    }
+}
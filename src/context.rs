@@ -0,0 +1,176 @@
+// Copyright 2024 David Araújo
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+
+use crate::command::{quote, Cmd};
+use crate::error::{Error, Result};
+use crate::output::Output;
+
+/// Where LXC keeps containers unless `--lxcpath` says otherwise.
+pub const DEFAULT_LXCPATH: &str = "/var/lib/lxc";
+
+/// Global options of one cmt invocation, shared by every lxc call it makes.
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    pub lxcpath: Option<String>,
+    pub logfile: Option<String>,
+    pub logpriority: Option<String>,
+    /// Print every command and file change instead of carrying it out.
+    pub dry_run: bool,
+    pub output: Output,
+}
+
+impl Context {
+    /// The global options as accepted by every `lxc-*` tool.
+    pub fn lxc_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+
+        if let Some(lxcpath) = &self.lxcpath {
+            args.push(format!("--lxcpath={}", lxcpath));
+        }
+
+        if let Some(logfile) = &self.logfile {
+            args.push(format!("--logfile={}", logfile));
+        }
+
+        if let Some(logpriority) = &self.logpriority {
+            args.push(format!("--logpriority={}", logpriority));
+        }
+
+        args
+    }
+
+    pub fn lxcpath(&self) -> &Path {
+        Path::new(self.lxcpath.as_deref().unwrap_or(DEFAULT_LXCPATH))
+    }
+
+    /// `<lxcpath>/<name>`
+    pub fn container_dir(&self, name: &str) -> PathBuf {
+        self.lxcpath().join(name)
+    }
+
+    /// `<lxcpath>/<name>/config`
+    pub fn config_file(&self, name: &str) -> PathBuf {
+        self.container_dir(name).join("config")
+    }
+
+    /// `<lxcpath>/<name>/rootfs`, where lxc puts a rootfs unless told otherwise.
+    pub fn default_rootfs(&self, name: &str) -> PathBuf {
+        self.container_dir(name).join("rootfs")
+    }
+
+    // Everything below changes the host or a container. In dry-run mode it
+    // is printed as the equivalent shell command and nothing is touched.
+
+    /// Runs `cmd`, failing unless it exits successfully.
+    pub fn run(&self, cmd: &Cmd) -> Result<()> {
+        if self.dry_run {
+            println!("{}", cmd);
+            return Ok(());
+        }
+        cmd.run(&self.output)
+    }
+
+    /// Runs `cmd` and returns its exit status, a success in dry-run mode.
+    pub fn status(&self, cmd: &Cmd) -> Result<ExitStatus> {
+        if self.dry_run {
+            println!("{}", cmd);
+            return Ok(ExitStatus::from_raw(0));
+        }
+        cmd.status(&self.output)
+    }
+
+    pub fn create_dir_all(&self, path: &Path) -> Result<()> {
+        if path.exists() {
+            return Ok(());
+        }
+        if self.dry_run {
+            println!(
+                "{}",
+                Cmd::new("mkdir").arg("-p").arg(path.display().to_string())
+            );
+            return Ok(());
+        }
+        fs::create_dir_all(path).map_err(Error::io(path))
+    }
+
+    /// Appends `line` to the file at `path`, which must exist.
+    pub fn append_line(&self, path: &Path, line: &str) -> Result<()> {
+        if self.dry_run {
+            println!(
+                "{} >> {}",
+                Cmd::new("echo").arg(line),
+                quote(&path.display().to_string())
+            );
+            return Ok(());
+        }
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(path)
+            .map_err(Error::io(path))?;
+        writeln!(file, "{}", line).map_err(Error::io(path))
+    }
+
+    /// Writes `contents` to the file at `path`, replacing it if it exists,
+    /// with permission bits `mode`.
+    pub fn write_file(&self, path: &Path, contents: &str, mode: u32) -> Result<()> {
+        let display = quote(&path.display().to_string());
+        if self.dry_run {
+            println!("cat > {} <<'EOF'\n{}EOF", display, contents);
+            println!("chmod {:o} {}", mode, display);
+            return Ok(());
+        }
+        fs::write(path, contents)
+            .and_then(|_| fs::set_permissions(path, fs::Permissions::from_mode(mode)))
+            .map_err(Error::io(path))
+    }
+
+    /// Gives the file at `path` to `uid` and `gid`.
+    pub fn chown(&self, path: &Path, uid: u32, gid: u32) -> Result<()> {
+        if self.dry_run {
+            println!(
+                "{}",
+                Cmd::new("chown")
+                    .arg(format!("{}:{}", uid, gid))
+                    .arg(path.display().to_string())
+            );
+            return Ok(());
+        }
+        std::os::unix::fs::chown(path, Some(uid), Some(gid)).map_err(Error::io(path))
+    }
+
+    /// Makes `link` a symbolic link to `target`, replacing whatever is there.
+    pub fn symlink(&self, target: &str, link: &Path) -> Result<()> {
+        if self.dry_run {
+            println!(
+                "{}",
+                Cmd::new("ln")
+                    .arg("-sf")
+                    .arg(target)
+                    .arg(link.display().to_string())
+            );
+            return Ok(());
+        }
+        if link.symlink_metadata().is_ok() {
+            fs::remove_file(link).map_err(Error::io(link))?;
+        }
+        std::os::unix::fs::symlink(target, link).map_err(Error::io(link))
+    }
+}
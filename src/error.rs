@@ -0,0 +1,116 @@
+// Copyright 2024 David Araújo
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+
+use crate::lxcfile::LxcFileError;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A program could not be started at all.
+    Spawn {
+        program: String,
+        source: io::Error,
+    },
+    /// A program ran but did not exit successfully.
+    Failed {
+        cmd: String,
+        status: ExitStatus,
+    },
+    /// A file or directory on the host could not be read or written.
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    LxcFile(LxcFileError),
+    /// An argument or value cmt cannot make sense of.
+    Invalid(String),
+    /// The runtime refused an operation without an exit status to report.
+    Runtime(String),
+    /// A build step failed, named after its place in the LXCfile such as
+    /// `run[2]`.
+    Step {
+        step: String,
+        source: Box<Error>,
+    },
+}
+
+impl Error {
+    /// Wraps an I/O error on `path`, for use with `map_err`.
+    pub fn io(path: impl Into<PathBuf>) -> impl FnOnce(io::Error) -> Error {
+        let path = path.into();
+        move |source| Error::Io { path, source }
+    }
+
+    /// Names the build step an error comes from, for use with `map_err`.
+    pub fn step(step: impl Into<String>) -> impl FnOnce(Error) -> Error {
+        let step = step.into();
+        move |source| Error::Step {
+            step,
+            source: Box::new(source),
+        }
+    }
+
+    /// Process exit code for cmt. A failed tool passes its own code
+    /// through, following the shell's conventions otherwise.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Spawn { source, .. } if source.kind() == io::ErrorKind::NotFound => 127,
+            Error::Spawn { .. } => 126,
+            Error::Failed { status, .. } => exit_code(*status),
+            Error::LxcFile(_) | Error::Invalid(_) => 2,
+            Error::Io { .. } | Error::Runtime(_) => 1,
+            Error::Step { source, .. } => source.exit_code(),
+        }
+    }
+}
+
+/// Exit code of a finished process, `128 + signal` if it was killed.
+pub fn exit_code(status: ExitStatus) -> u8 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code as u8,
+        (None, Some(signal)) => 128u8.saturating_add(signal as u8),
+        (None, None) => 1,
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Spawn { program, source } => write!(f, "failed to run {}: {}", program, source),
+            Error::Failed { cmd, status } => match status.signal() {
+                Some(signal) => write!(f, "`{}` was killed by signal {}", cmd, signal),
+                None => write!(f, "`{}` exited with status {}", cmd, exit_code(*status)),
+            },
+            Error::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            Error::LxcFile(e) => write!(f, "invalid LXCfile: {}", e),
+            Error::Invalid(message) | Error::Runtime(message) => write!(f, "{}", message),
+            Error::Step { step, source } => write!(f, "build step {} failed: {}", step, source),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<LxcFileError> for Error {
+    fn from(e: LxcFileError) -> Error {
+        Error::LxcFile(e)
+    }
+}
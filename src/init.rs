@@ -0,0 +1,229 @@
+// Copyright 2024 David Araújo
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+use crate::context::Context;
+use crate::error::Result;
+use crate::lxcfile::Restart;
+
+/// Script the entrypoint is written to, inside the container.
+pub const ENTRYPOINT: &str = "/usr/local/bin/lxcapp";
+
+/// The init system of a rootfs, which starts the entrypoint at boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitSystem {
+    Systemd,
+    OpenRc,
+    /// BusyBox `init` reading `/etc/inittab`
+    Busybox,
+    SysVinit,
+}
+
+impl fmt::Display for InitSystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InitSystem::Systemd => "systemd",
+            InitSystem::OpenRc => "OpenRC",
+            InitSystem::Busybox => "BusyBox init",
+            InitSystem::SysVinit => "SysVinit",
+        })
+    }
+}
+
+impl InitSystem {
+    /// Looks at what `/sbin/init` is in `rootfs`. Alpine boots OpenRC from
+    /// BusyBox init, so OpenRC is looked for before BusyBox.
+    pub fn detect(rootfs: &Path) -> Option<InitSystem> {
+        let init = resolve(rootfs, "/sbin/init");
+        let init_name = init.file_name().and_then(|name| name.to_str());
+        if init_name == Some("systemd") {
+            Some(InitSystem::Systemd)
+        } else if resolve(rootfs, "/sbin/openrc-run").exists() {
+            Some(InitSystem::OpenRc)
+        } else if init_name == Some("busybox") {
+            Some(InitSystem::Busybox)
+        } else if init.exists() && resolve(rootfs, "/etc/inittab").exists() {
+            Some(InitSystem::SysVinit)
+        } else {
+            None
+        }
+    }
+
+    /// Installs `ENTRYPOINT` as a service started at boot, supervised
+    /// when `restart` is `always`.
+    pub fn install(self, ctx: &Context, rootfs: &Path, restart: Restart) -> Result<()> {
+        let always = restart == Restart::Always;
+        match self {
+            InitSystem::Systemd => {
+                let unit = resolve(rootfs, "/etc/systemd/system");
+                ctx.create_dir_all(&unit)?;
+                ctx.write_file(
+                    &unit.join("lxcapp.service"),
+                    &format!(
+                        "[Unit]\n\
+                         Description=LXCfile entrypoint\n\
+                         After=network.target\n\
+                         \n\
+                         [Service]\n\
+                         ExecStart={}\n\
+                         Restart={}\n\
+                         \n\
+                         [Install]\n\
+                         WantedBy=multi-user.target\n",
+                        ENTRYPOINT,
+                        if always { "always" } else { "no" }
+                    ),
+                    0o644,
+                )?;
+                // What `systemctl enable` would do
+                let wants = unit.join("multi-user.target.wants");
+                ctx.create_dir_all(&wants)?;
+                ctx.symlink(
+                    "/etc/systemd/system/lxcapp.service",
+                    &wants.join("lxcapp.service"),
+                )
+            }
+            InitSystem::OpenRc => {
+                let supervisor = if always {
+                    "supervisor=supervise-daemon\nrespawn_max=0\n"
+                } else {
+                    "command_background=true\n"
+                };
+                let init_d = resolve(rootfs, "/etc/init.d");
+                ctx.create_dir_all(&init_d)?;
+                ctx.write_file(
+                    &init_d.join("lxcapp"),
+                    &format!(
+                        "#!/sbin/openrc-run\n\
+                         \n\
+                         description=\"LXCfile entrypoint\"\n\
+                         command=\"{}\"\n\
+                         pidfile=\"/run/lxcapp.pid\"\n\
+                         {}\n\
+                         depend() {{\n\
+                         \tafter net\n\
+                         }}\n",
+                        ENTRYPOINT, supervisor
+                    ),
+                    0o755,
+                )?;
+                // What `rc-update add lxcapp default` would do
+                let runlevel = resolve(rootfs, "/etc/runlevels/default");
+                ctx.create_dir_all(&runlevel)?;
+                ctx.symlink("/etc/init.d/lxcapp", &runlevel.join("lxcapp"))
+            }
+            InitSystem::Busybox | InitSystem::SysVinit => {
+                let action = if always { "respawn" } else { "once" };
+                let line = match self {
+                    InitSystem::Busybox => format!("::{}:{}", action, ENTRYPOINT),
+                    _ => format!("lxca:2345:{}:{}", action, ENTRYPOINT),
+                };
+                let inittab = resolve(rootfs, "/etc/inittab");
+                let installed = std::fs::read_to_string(&inittab)
+                    .is_ok_and(|contents| contents.lines().any(|l| l.ends_with(ENTRYPOINT)));
+                if installed {
+                    return Ok(());
+                }
+                ctx.append_line(&inittab, &line)
+            }
+        }
+    }
+}
+
+/// Host path of `path` inside `rootfs`, following symbolic links as the
+/// container would see them rather than as the host does.
+pub fn resolve(rootfs: &Path, path: &str) -> PathBuf {
+    let mut resolved = PathBuf::from("/");
+    let mut pending: Vec<String> = vec![path.to_string()];
+    // Same limit as the kernel's ELOOP
+    let mut links = 0;
+
+    while let Some(path) = pending.pop() {
+        let mut components: Vec<Component> = Path::new(&path).components().collect();
+        components.reverse();
+        while let Some(component) = components.pop() {
+            match component {
+                Component::RootDir => resolved = PathBuf::from("/"),
+                Component::ParentDir => {
+                    resolved.pop();
+                }
+                Component::Normal(name) => {
+                    let next = resolved.join(name);
+                    let host = rootfs.join(next.strip_prefix("/").unwrap());
+                    match std::fs::read_link(&host) {
+                        Ok(target) if links < 40 => {
+                            links += 1;
+                            // Resolve the target, then whatever is left
+                            let rest: PathBuf = components.iter().rev().collect();
+                            if !rest.as_os_str().is_empty() {
+                                pending.push(rest.display().to_string());
+                            }
+                            pending.push(target.display().to_string());
+                            break;
+                        }
+                        _ => resolved = next,
+                    }
+                }
+                Component::CurDir | Component::Prefix(_) => {}
+            }
+        }
+    }
+
+    rootfs.join(resolved.strip_prefix("/").unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn detects_init_through_links_inside_the_rootfs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = |name: &str| {
+            let rootfs = tmp.path().join(name);
+            std::fs::create_dir_all(rootfs.join("usr/sbin")).unwrap();
+            std::fs::create_dir_all(rootfs.join("bin")).unwrap();
+            std::fs::create_dir_all(rootfs.join("etc")).unwrap();
+            rootfs
+        };
+
+        // usrmerge links, with an absolute target that must not leave it
+        let debian = rootfs("debian");
+        symlink("/usr/sbin", debian.join("sbin")).unwrap();
+        symlink("../lib/systemd/systemd", debian.join("usr/sbin/init")).unwrap();
+        assert_eq!(InitSystem::detect(&debian), Some(InitSystem::Systemd));
+        assert_eq!(
+            resolve(&debian, "/sbin/init"),
+            debian.join("usr/lib/systemd/systemd")
+        );
+
+        let alpine = rootfs("alpine");
+        std::fs::create_dir(alpine.join("sbin")).unwrap();
+        symlink("/bin/busybox", alpine.join("sbin/init")).unwrap();
+        std::fs::write(alpine.join("sbin/openrc-run"), "").unwrap();
+        assert_eq!(InitSystem::detect(&alpine), Some(InitSystem::OpenRc));
+        std::fs::remove_file(alpine.join("sbin/openrc-run")).unwrap();
+        assert_eq!(InitSystem::detect(&alpine), Some(InitSystem::Busybox));
+
+        let devuan = rootfs("devuan");
+        std::fs::create_dir(devuan.join("sbin")).unwrap();
+        std::fs::write(devuan.join("sbin/init"), "").unwrap();
+        assert_eq!(InitSystem::detect(&devuan), None);
+        std::fs::write(devuan.join("etc/inittab"), "").unwrap();
+        assert_eq!(InitSystem::detect(&devuan), Some(InitSystem::SysVinit));
+    }
+}
@@ -0,0 +1,254 @@
+// Copyright 2024 David Araújo
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+/// Format of the data printed by `--output`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Json,
+    Yaml,
+    /// Aligned columns with a header line
+    Table,
+}
+
+/// What cmt knows about one container.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ContainerInfo {
+    pub name: String,
+    pub state: String,
+    pub pid: Option<i32>,
+    pub ips: Vec<String>,
+    /// Memory in use, in bytes
+    pub memory: Option<u64>,
+    pub groups: Vec<String>,
+    pub autostart: bool,
+}
+
+impl ContainerInfo {
+    /// Reads the `Key: value` status report printed by `lxc-info`.
+    pub fn from_report(name: &str, report: &str) -> ContainerInfo {
+        let mut info = ContainerInfo {
+            name: name.to_string(),
+            ..Default::default()
+        };
+
+        for line in report.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match key.trim() {
+                "State" => info.state = value.to_string(),
+                "PID" => info.pid = value.parse().ok(),
+                "IP" => info.ips.push(value.to_string()),
+                "Memory use" => info.memory = parse_size(value),
+                _ => {}
+            }
+        }
+
+        info
+    }
+}
+
+/// A single configuration or cgroup item of a container.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ConfigItem {
+    pub name: String,
+    pub key: String,
+    pub value: String,
+}
+
+/// Something that can be printed as a row of `--output table`.
+pub trait Row {
+    fn headers() -> &'static [&'static str];
+
+    fn cells(&self) -> Vec<String>;
+}
+
+impl Row for ContainerInfo {
+    fn headers() -> &'static [&'static str] {
+        &[
+            "NAME",
+            "STATE",
+            "PID",
+            "IPS",
+            "MEMORY",
+            "GROUPS",
+            "AUTOSTART",
+        ]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.name.clone(),
+            self.state.clone(),
+            self.pid.map_or("-".to_string(), |pid| pid.to_string()),
+            or_dash(self.ips.join(",")),
+            self.memory.map_or("-".to_string(), human_size),
+            or_dash(self.groups.join(",")),
+            if self.autostart { "yes" } else { "no" }.to_string(),
+        ]
+    }
+}
+
+impl Row for ConfigItem {
+    fn headers() -> &'static [&'static str] {
+        &["NAME", "KEY", "VALUE"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![self.name.clone(), self.key.clone(), self.value.clone()]
+    }
+}
+
+/// Renders `items` as a list in `format`.
+pub fn render_all<T: Serialize + Row>(format: OutputFormat, items: &[T]) -> Result<String> {
+    match format {
+        OutputFormat::Json => serde_json::to_string_pretty(items)
+            .map(|json| json + "\n")
+            .map_err(|e| Error::Runtime(e.to_string())),
+        OutputFormat::Yaml => {
+            serde_yaml::to_string(items).map_err(|e| Error::Runtime(e.to_string()))
+        }
+        OutputFormat::Table => Ok(table(items)),
+    }
+}
+
+/// Renders a single `item` in `format`, as an object rather than a list.
+pub fn render_one<T: Serialize + Row>(format: OutputFormat, item: &T) -> Result<String> {
+    match format {
+        OutputFormat::Json => serde_json::to_string_pretty(item)
+            .map(|json| json + "\n")
+            .map_err(|e| Error::Runtime(e.to_string())),
+        OutputFormat::Yaml => {
+            serde_yaml::to_string(item).map_err(|e| Error::Runtime(e.to_string()))
+        }
+        OutputFormat::Table => Ok(table(std::slice::from_ref(item))),
+    }
+}
+
+/// Renders `items` as `--output table` does, one aligned row each.
+pub fn table<T: Row>(items: &[T]) -> String {
+    let headers: Vec<String> = T::headers().iter().map(|h| h.to_string()).collect();
+    let rows: Vec<Vec<String>> = items.iter().map(Row::cells).collect();
+
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut output = String::new();
+    for row in std::iter::once(&headers).chain(&rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        output.push_str(line.join("  ").trim_end());
+        output.push('\n');
+    }
+    output
+}
+
+fn or_dash(value: String) -> String {
+    if value.is_empty() {
+        "-".to_string()
+    } else {
+        value
+    }
+}
+
+const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+// Reads `11010048` or lxc-info's humanized `10.50 MiB`
+fn parse_size(value: &str) -> Option<u64> {
+    let (number, unit) = value.split_once(' ').unwrap_or((value, "B"));
+    let power = UNITS.iter().position(|u| *u == unit.trim())?;
+    let number: f64 = number.parse().ok()?;
+    Some((number * 1024f64.powi(power as i32)) as u64)
+}
+
+fn human_size(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.2} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_lxc_info_reports() {
+        let report = "Name:           web\n\
+                      State:          RUNNING\n\
+                      PID:            4242\n\
+                      IP:             10.0.3.15\n\
+                      IP:             fd42::15\n\
+                      CPU use:        1.52 seconds\n\
+                      Memory use:     10.50 MiB\n\
+                      Link:           vethW3B1\n";
+
+        assert_eq!(
+            ContainerInfo::from_report("web", report),
+            ContainerInfo {
+                name: "web".to_string(),
+                state: "RUNNING".to_string(),
+                pid: Some(4242),
+                ips: vec!["10.0.3.15".to_string(), "fd42::15".to_string()],
+                memory: Some(11010048),
+                groups: vec![],
+                autostart: false,
+            }
+        );
+    }
+
+    #[test]
+    fn tables_align_columns() {
+        let items = [
+            ConfigItem {
+                name: "web".to_string(),
+                key: "memory.max".to_string(),
+                value: "256M".to_string(),
+            },
+            ConfigItem {
+                name: "database".to_string(),
+                key: "cpuset.cpus".to_string(),
+                value: "0,1".to_string(),
+            },
+        ];
+
+        assert_eq!(
+            render_all(OutputFormat::Table, &items).unwrap(),
+            "NAME      KEY          VALUE\n\
+             web       memory.max   256M\n\
+             database  cpuset.cpus  0,1\n"
+        );
+    }
+}
@@ -0,0 +1,1021 @@
+// Copyright 2024 David Araújo
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+/// Stands for the number of a replica in `[[shared]]` host paths.
+pub const REPLICA: &str = "${REPLICA}";
+
+/// File in a built container's directory holding the resolved LXCfile it
+/// was built from, for other LXCfiles to inherit `from`.
+pub const BUILT_FROM: &str = "cmt-lxcfile.toml";
+
+/// Container definition read from an `LXCfile.toml`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LxcFile {
+    /// Build arguments and their defaults, used as `${NAME}` elsewhere
+    #[serde(default)]
+    pub args: BTreeMap<String, String>,
+    pub name: String,
+    /// Number of identical containers, `NAME-1` to `NAME-N`
+    pub replicas: Option<u32>,
+    /// Containers built before this one, to copy artifacts out of
+    #[serde(default)]
+    pub stage: Vec<Stage>,
+    pub image: Image,
+    pub entrypoint: Option<String>,
+    #[serde(default)]
+    pub restart: Restart,
+    /// Variables set for the container, its `[[run]]` steps and entrypoint
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Defaults for the `user` and `workdir` of each `[[run]]` step
+    pub user: Option<String>,
+    pub workdir: Option<String>,
+    #[serde(default)]
+    pub limits: BTreeMap<String, LimitValue>,
+    /// Interfaces replacing those of the image's configuration
+    #[serde(default)]
+    pub network: Vec<Network>,
+    #[serde(default)]
+    pub copy: Vec<Copy>,
+    #[serde(default)]
+    pub template: Vec<Template>,
+    #[serde(default)]
+    pub shared: Vec<Shared>,
+    #[serde(default)]
+    pub device: Vec<Device>,
+    #[serde(default)]
+    pub run: Vec<Run>,
+    /// Directories of the LXCfiles inherited `from`, which their relative
+    /// paths were made absolute against
+    #[serde(skip)]
+    pub inherited: Vec<PathBuf>,
+}
+
+/// When the init system starts the entrypoint again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Restart {
+    /// Started once at boot
+    #[default]
+    No,
+    /// Supervised and started again whenever it exits
+    Always,
+}
+
+/// A `[[stage]]`: a temporary container built from its own image, whose
+/// rootfs later stages and the final container can copy from.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Stage {
+    pub name: String,
+    pub image: Image,
+    #[serde(default)]
+    pub copy: Vec<Copy>,
+    #[serde(default)]
+    pub run: Vec<Run>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Image {
+    pub distro: String,
+    pub release: String,
+    pub arch: String,
+    pub config: Option<String>,
+    pub dir: Option<String>,
+    pub network: Option<String>,
+}
+
+/// A `[[network]]` interface, written as `lxc.net.N.*` keys.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Network {
+    #[serde(rename = "type")]
+    pub kind: NetworkType,
+    /// Bridge of a `veth`, or host interface of a `macvlan`
+    pub link: Option<String>,
+    pub hwaddr: Option<String>,
+    /// Static address with its prefix length, such as `10.0.3.10/24`
+    pub ipv4: Option<String>,
+    pub ipv4_gateway: Option<String>,
+    pub ipv6: Option<String>,
+    pub ipv6_gateway: Option<String>,
+    pub mtu: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkType {
+    Veth,
+    Macvlan,
+    /// No interface but the loopback
+    None,
+}
+
+impl fmt::Display for NetworkType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NetworkType::Veth => "veth",
+            NetworkType::Macvlan => "macvlan",
+            NetworkType::None => "none",
+        })
+    }
+}
+
+impl Network {
+    /// `lxc.net.N.*` keys without their `lxc.net.N.` prefix, and values.
+    pub fn config(&self) -> Vec<(&'static str, String)> {
+        let mut items = vec![("type", self.kind.to_string())];
+        if self.kind == NetworkType::None {
+            return items;
+        }
+        items.push(("flags", "up".to_string()));
+        for (key, value) in [
+            ("link", &self.link),
+            ("hwaddr", &self.hwaddr),
+            ("ipv4.address", &self.ipv4),
+            ("ipv4.gateway", &self.ipv4_gateway),
+            ("ipv6.address", &self.ipv6),
+            ("ipv6.gateway", &self.ipv6_gateway),
+        ] {
+            if let Some(value) = value {
+                items.push((key, value.clone()));
+            }
+        }
+        if let Some(mtu) = self.mtu {
+            items.push(("mtu", mtu.to_string()));
+        }
+        items
+    }
+}
+
+/// Value of a `[limits]` entry, written as either a string or an integer.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum LimitValue {
+    Integer(i64),
+    String(String),
+}
+
+impl fmt::Display for LimitValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitValue::Integer(value) => write!(f, "{}", value),
+            LimitValue::String(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Copy {
+    /// Stage to copy out of, in which case `host` is a path in its rootfs
+    pub from: Option<String>,
+    pub host: String,
+    pub container: String,
+    #[serde(default)]
+    pub archive: bool,
+    #[serde(default)]
+    pub follow_link: bool,
+}
+
+/// A `[[template]]`: a file of the build context written into the
+/// container with `${NAME}` replaced by `[args]` and `[env]` values.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Template {
+    pub source: String,
+    pub container: String,
+    /// Permission bits in octal, `0644` when unset
+    pub mode: Option<String>,
+    /// `USER` or `USER:GROUP`, names or ids in the container, root when unset
+    pub owner: Option<String>,
+}
+
+impl Template {
+    /// `mode` as permission bits.
+    pub fn mode(&self) -> Result<u32, String> {
+        let Some(mode) = &self.mode else {
+            return Ok(0o644);
+        };
+        u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .ok_or_else(|| format!("`{}` is not an octal mode", mode))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Shared {
+    pub host: String,
+    pub container: String,
+}
+
+/// A `[[device]]`: a device node of the host, allowed by the devices
+/// cgroup and bind mounted into the container.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Device {
+    /// Device node on the host, such as `/dev/fuse`
+    pub path: String,
+    /// Where it appears in the container, `path` when unset
+    pub container: Option<String>,
+    /// Any of `r`, `w` and `m` (mknod), `rwm` when unset
+    pub access: Option<String>,
+}
+
+impl Device {
+    pub fn access(&self) -> &str {
+        self.access.as_deref().unwrap_or("rwm")
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Run {
+    pub cmd: RunCmd,
+    /// Name or uid in the container's `/etc/passwd`, root when unset
+    pub user: Option<String>,
+    /// Name or gid in the container's `/etc/group`, otherwise the
+    /// primary group of `user`
+    pub group: Option<String>,
+    /// Directory the command starts in, `/` when unset
+    pub workdir: Option<String>,
+}
+
+/// A `[[run]]` command, either a line for the container's shell or an
+/// exact argument vector (`cmd = ["make", "install"]`).
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum RunCmd {
+    Shell(String),
+    Exec(Vec<String>),
+}
+
+impl RunCmd {
+    /// Argument vector to hand to `lxc-attach` after `--`.
+    pub fn argv(&self) -> Vec<String> {
+        match self {
+            RunCmd::Shell(line) => vec!["/bin/sh".to_string(), "-c".to_string(), line.clone()],
+            RunCmd::Exec(argv) => argv.clone(),
+        }
+    }
+}
+
+impl fmt::Display for RunCmd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunCmd::Shell(line) => write!(f, "{}", line),
+            RunCmd::Exec(argv) => write!(f, "{}", argv.join(" ")),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LxcFileError {
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    /// A `${NAME}` that could not be substituted, or a bad `--build-arg`.
+    Args { path: PathBuf, message: String },
+    /// A `from` that cannot be followed, or a merged file that does not
+    /// make an LXCfile.
+    Inherit { path: PathBuf, message: String },
+}
+
+impl fmt::Display for LxcFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LxcFileError::Read { path, source } => {
+                write!(f, "{}: {}", path.display(), source)
+            }
+            LxcFileError::Parse {
+                path,
+                line,
+                column,
+                message,
+            } => write!(f, "{}:{}:{}: {}", path.display(), line, column, message),
+            LxcFileError::Args { path, message } | LxcFileError::Inherit { path, message } => {
+                write!(f, "{}: {}", path.display(), message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LxcFileError {}
+
+impl LxcFile {
+    /// Reads and validates the LXCfile at `path`, merged with whatever it
+    /// inherits `from`, then substitutes its build arguments, `build_args`
+    /// taking precedence over `[args]`, and gives `[[run]]` steps the
+    /// top-level `user` and `workdir`. Built containers are looked for in
+    /// `lxcpath`.
+    pub fn load(
+        path: &Path,
+        build_args: &[(String, String)],
+        lxcpath: &Path,
+    ) -> Result<LxcFile, LxcFileError> {
+        let contents = read(path)?;
+
+        // Parsed as written when it stands alone, so errors have a position
+        let mut file = if parse_table(path, &contents)?.contains_key("from") {
+            let (resolved, inherited) = LxcFile::inherit(path, lxcpath)?;
+            let mut file: LxcFile =
+                toml::Value::Table(resolved)
+                    .try_into()
+                    .map_err(|e: toml::de::Error| LxcFileError::Inherit {
+                        path: path.to_path_buf(),
+                        message: e.message().to_string(),
+                    })?;
+            file.inherited = inherited;
+            file
+        } else {
+            LxcFile::parse(path, &contents)?
+        };
+        file.interpolate(build_args)
+            .map_err(|message| LxcFileError::Args {
+                path: path.to_path_buf(),
+                message,
+            })?;
+        for run in &mut file.run {
+            run.user = run.user.take().or_else(|| file.user.clone());
+            run.workdir = run.workdir.take().or_else(|| file.workdir.clone());
+        }
+        Ok(file)
+    }
+
+    /// The LXCfile at `path` as a table, merged over the LXCfile it
+    /// inherits `from` and so on. Arrays such as `[[run]]` are appended to
+    /// the inherited ones, tables such as `[limits]` override them key by
+    /// key and any other value replaces the inherited one.
+    ///
+    /// `from` is a path relative to the file, or the name of a container
+    /// in `lxcpath` that `cmt build` built. Relative host paths of the
+    /// inherited files are made absolute against their own directory.
+    pub fn resolve(path: &Path, lxcpath: &Path) -> Result<toml::Table, LxcFileError> {
+        LxcFile::inherit(path, lxcpath).map(|(resolved, _)| resolved)
+    }
+
+    // The resolved table and the directories of the files it inherits
+    fn inherit(path: &Path, lxcpath: &Path) -> Result<(toml::Table, Vec<PathBuf>), LxcFileError> {
+        let mut chain: Vec<PathBuf> = Vec::new();
+        let mut inherited = Vec::new();
+        let mut tables = Vec::new();
+        let mut next = Some(path.to_path_buf());
+        while let Some(path) = next.take() {
+            let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+            if chain.contains(&canonical) {
+                return Err(LxcFileError::Inherit {
+                    path,
+                    message: "inherits from itself".to_string(),
+                });
+            }
+            chain.push(canonical);
+
+            let mut table = parse_table(&path, &read(&path)?)?;
+            if !tables.is_empty() {
+                // Canonical, as the file was just read
+                let dir = chain[chain.len() - 1].parent().unwrap_or(Path::new("/"));
+                rebase(&mut table, dir);
+                inherited.push(dir.to_path_buf());
+            }
+            next = match table.remove("from") {
+                None => None,
+                Some(toml::Value::String(from))
+                    if from.contains('/') || from.ends_with(".toml") =>
+                {
+                    Some(path.parent().unwrap_or(Path::new(".")).join(from))
+                }
+                Some(toml::Value::String(container)) => {
+                    let built = lxcpath.join(&container).join(BUILT_FROM);
+                    if !built.exists() {
+                        let first_replica = format!("{}-1", container);
+                        let message = if lxcpath.join(&first_replica).join(BUILT_FROM).exists() {
+                            format!(
+                                "container `{}` was built as replicas, inherit from `{}`",
+                                container, first_replica
+                            )
+                        } else {
+                            format!("container `{}` was not built by cmt build", container)
+                        };
+                        return Err(LxcFileError::Inherit { path, message });
+                    }
+                    Some(built)
+                }
+                Some(_) => {
+                    return Err(LxcFileError::Inherit {
+                        path,
+                        message: "`from` is not a string".to_string(),
+                    })
+                }
+            };
+            tables.push(table);
+        }
+
+        let mut resolved = toml::Table::new();
+        for table in tables.into_iter().rev() {
+            merge(&mut resolved, table);
+        }
+        Ok((resolved, inherited))
+    }
+
+    /// Replaces `${NAME}` with the value of build argument NAME in every
+    /// string field. `$$` stands for a literal `$`.
+    pub fn interpolate(&mut self, build_args: &[(String, String)]) -> Result<(), String> {
+        let mut vars = self.args.clone();
+        for (key, value) in build_args {
+            if !vars.contains_key(key) {
+                return Err(format!(
+                    "build argument `{}` is not declared in [args]",
+                    key
+                ));
+            }
+            vars.insert(key.clone(), value.clone());
+        }
+        // Left for each replica to fill in
+        let mut replica_vars = vars.clone();
+        replica_vars
+            .entry("REPLICA".to_string())
+            .or_insert_with(|| REPLICA.to_string());
+        for (field, value) in self.strings_mut() {
+            let vars = if field.starts_with("shared[") && field.ends_with(".host") {
+                &replica_vars
+            } else {
+                &vars
+            };
+            *value = substitute(value, vars, "build argument")
+                .map_err(|e| format!("{}: {}", field, e))?;
+        }
+        // What templates are rendered with
+        self.args = vars;
+        Ok(())
+    }
+
+    /// Parses `contents`, using `path` only to label errors.
+    pub fn parse(path: &Path, contents: &str) -> Result<LxcFile, LxcFileError> {
+        toml::from_str(contents).map_err(|e: toml::de::Error| {
+            let offset = e.span().map(|span| span.start).unwrap_or(0);
+            let (line, column) = line_column(contents, offset);
+            LxcFileError::Parse {
+                path: path.to_path_buf(),
+                line,
+                column,
+                message: e.message().to_string(),
+            }
+        })
+    }
+
+    /// `[env]` as `KEY=VALUE` items.
+    pub fn env_vars(&self) -> Vec<String> {
+        self.env
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect()
+    }
+
+    /// Values a `[[template]]` can use, `[env]` over `[args]`.
+    pub fn template_vars(&self) -> BTreeMap<String, String> {
+        let mut vars = self.args.clone();
+        vars.extend(self.env.clone());
+        vars
+    }
+
+    /// Name of the temporary container `stage` is built in.
+    pub fn stage_container(&self, stage: &Stage) -> String {
+        format!("{}-stage-{}", self.name, stage.name)
+    }
+
+    // Every string that build arguments apply to, with its place in the file
+    fn strings_mut(&mut self) -> Vec<(String, &mut String)> {
+        let mut strings = vec![("name".to_string(), &mut self.name)];
+        if let Some(entrypoint) = &mut self.entrypoint {
+            strings.push(("entrypoint".to_string(), entrypoint));
+        }
+        for (key, value) in self.env.iter_mut() {
+            strings.push((format!("env.{}", key), value));
+        }
+        for (field, value) in [("user", &mut self.user), ("workdir", &mut self.workdir)] {
+            if let Some(value) = value {
+                strings.push((field.to_string(), value));
+            }
+        }
+        for (i, stage) in self.stage.iter_mut().enumerate() {
+            let prefix = format!("stage[{}].", i + 1);
+            strings.push((format!("{}name", prefix), &mut stage.name));
+            image_strings(&mut strings, &prefix, &mut stage.image);
+            copy_strings(&mut strings, &prefix, &mut stage.copy);
+            run_strings(&mut strings, &prefix, &mut stage.run);
+        }
+        image_strings(&mut strings, "", &mut self.image);
+        for (key, value) in self.limits.iter_mut() {
+            if let LimitValue::String(value) = value {
+                strings.push((format!("limits.{}", key), value));
+            }
+        }
+        for (i, network) in self.network.iter_mut().enumerate() {
+            for (field, value) in [
+                ("link", &mut network.link),
+                ("hwaddr", &mut network.hwaddr),
+                ("ipv4", &mut network.ipv4),
+                ("ipv4_gateway", &mut network.ipv4_gateway),
+                ("ipv6", &mut network.ipv6),
+                ("ipv6_gateway", &mut network.ipv6_gateway),
+            ] {
+                if let Some(value) = value {
+                    strings.push((format!("network[{}].{}", i + 1, field), value));
+                }
+            }
+        }
+        copy_strings(&mut strings, "", &mut self.copy);
+        for (i, template) in self.template.iter_mut().enumerate() {
+            let field = format!("template[{}]", i + 1);
+            strings.push((format!("{}.source", field), &mut template.source));
+            strings.push((format!("{}.container", field), &mut template.container));
+            for (name, value) in [("mode", &mut template.mode), ("owner", &mut template.owner)] {
+                if let Some(value) = value {
+                    strings.push((format!("{}.{}", field, name), value));
+                }
+            }
+        }
+        for (i, shared) in self.shared.iter_mut().enumerate() {
+            strings.push((format!("shared[{}].host", i + 1), &mut shared.host));
+            strings.push((
+                format!("shared[{}].container", i + 1),
+                &mut shared.container,
+            ));
+        }
+        for (i, device) in self.device.iter_mut().enumerate() {
+            strings.push((format!("device[{}].path", i + 1), &mut device.path));
+            if let Some(container) = &mut device.container {
+                strings.push((format!("device[{}].container", i + 1), container));
+            }
+        }
+        run_strings(&mut strings, "", &mut self.run);
+        strings
+    }
+}
+
+fn read(path: &Path) -> Result<String, LxcFileError> {
+    std::fs::read_to_string(path).map_err(|source| LxcFileError::Read {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+// Any TOML table, as inherited files need not be whole LXCfiles
+fn parse_table(path: &Path, contents: &str) -> Result<toml::Table, LxcFileError> {
+    contents.parse().map_err(|e: toml::de::Error| {
+        let offset = e.span().map(|span| span.start).unwrap_or(0);
+        let (line, column) = line_column(contents, offset);
+        LxcFileError::Parse {
+            path: path.to_path_buf(),
+            line,
+            column,
+            message: e.message().to_string(),
+        }
+    })
+}
+
+/// Makes the relative `[[copy]]` and `[[shared]]` hosts and `[[template]]`
+/// sources of an LXCfile table absolute against `dir`, the directory they
+/// are relative to. Copies `from` a stage are left alone, and so are values
+/// that start with `${`, as only their build argument says where they are.
+pub fn rebase(table: &mut toml::Table, dir: &Path) {
+    for stage in tables_mut(table, "stage") {
+        rebase(stage, dir);
+    }
+    for (array, key) in [("copy", "host"), ("template", "source"), ("shared", "host")] {
+        for item in tables_mut(table, array) {
+            if item.contains_key("from") {
+                continue;
+            }
+            if let Some(toml::Value::String(host)) = item.get_mut(key) {
+                if Path::new(host.as_str()).is_relative() && !host.starts_with("${") {
+                    *host = dir.join(&host).to_string_lossy().into_owned();
+                }
+            }
+        }
+    }
+}
+
+// The tables of array `key`, if there is one
+fn tables_mut<'a>(
+    table: &'a mut toml::Table,
+    key: &str,
+) -> impl Iterator<Item = &'a mut toml::Table> {
+    table
+        .get_mut(key)
+        .and_then(toml::Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(toml::Value::as_table_mut)
+}
+
+// Merges `child` over `parent`
+fn merge(parent: &mut toml::Table, child: toml::Table) {
+    for (key, value) in child {
+        match (parent.get_mut(&key), value) {
+            (Some(toml::Value::Array(inherited)), toml::Value::Array(items)) => {
+                inherited.extend(items)
+            }
+            (Some(toml::Value::Table(inherited)), toml::Value::Table(table)) => {
+                merge(inherited, table)
+            }
+            (_, value) => {
+                parent.insert(key, value);
+            }
+        }
+    }
+}
+
+fn image_strings<'a>(
+    strings: &mut Vec<(String, &'a mut String)>,
+    prefix: &str,
+    image: &'a mut Image,
+) {
+    strings.push((format!("{}image.distro", prefix), &mut image.distro));
+    strings.push((format!("{}image.release", prefix), &mut image.release));
+    strings.push((format!("{}image.arch", prefix), &mut image.arch));
+    for (field, value) in [
+        ("config", &mut image.config),
+        ("dir", &mut image.dir),
+        ("network", &mut image.network),
+    ] {
+        if let Some(value) = value {
+            strings.push((format!("{}image.{}", prefix, field), value));
+        }
+    }
+}
+
+fn copy_strings<'a>(
+    strings: &mut Vec<(String, &'a mut String)>,
+    prefix: &str,
+    copies: &'a mut [Copy],
+) {
+    for (i, copy) in copies.iter_mut().enumerate() {
+        let field = format!("{}copy[{}]", prefix, i + 1);
+        if let Some(from) = &mut copy.from {
+            strings.push((format!("{}.from", field), from));
+        }
+        strings.push((format!("{}.host", field), &mut copy.host));
+        strings.push((format!("{}.container", field), &mut copy.container));
+    }
+}
+
+fn run_strings<'a>(strings: &mut Vec<(String, &'a mut String)>, prefix: &str, runs: &'a mut [Run]) {
+    for (i, run) in runs.iter_mut().enumerate() {
+        let field = format!("{}run[{}]", prefix, i + 1);
+        match &mut run.cmd {
+            RunCmd::Shell(line) => strings.push((format!("{}.cmd", field), line)),
+            RunCmd::Exec(argv) => {
+                strings.extend(argv.iter_mut().map(|arg| (format!("{}.cmd", field), arg)))
+            }
+        }
+        for (name, value) in [
+            ("user", &mut run.user),
+            ("group", &mut run.group),
+            ("workdir", &mut run.workdir),
+        ] {
+            if let Some(value) = value {
+                strings.push((format!("{}.{}", field, name), value));
+            }
+        }
+    }
+}
+
+/// Expands `${NAME}` and `$$` in `value`. A `$` followed by anything else
+/// is kept, so shell commands can still use `$HOME`. A NAME missing from
+/// `vars` is reported as an undefined `kind`, such as "build argument".
+pub fn substitute(
+    value: &str,
+    vars: &BTreeMap<String, String>,
+    kind: &str,
+) -> Result<String, String> {
+    let mut output = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            output.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .ok_or_else(|| format!("unterminated `${{` in `{}`", value))?;
+            let name = &after[..end];
+            let var = vars
+                .get(name)
+                .ok_or_else(|| format!("undefined {} `{}`", kind, name))?;
+            output.push_str(var);
+            rest = &after[end + 1..];
+        } else {
+            output.push('$');
+            rest = &rest[1..];
+        }
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Converts a byte offset into a 1-based line and column.
+pub fn line_column(contents: &str, offset: usize) -> (usize, usize) {
+    let before = &contents[..offset.min(contents.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_keys_and_wrong_types_are_reported_at_their_position() {
+        let parse = |contents: &str| {
+            LxcFile::parse(Path::new("LXCfile.toml"), contents)
+                .unwrap_err()
+                .to_string()
+        };
+        let image = "[image]\ndistro = \"alpine\"\nrelease = \"3.19\"\narch = \"amd64\"\n";
+
+        let err = parse(&format!(
+            "name = \"web\"\n{}\n[[run]]\ncmnd = \"make\"\n",
+            image
+        ));
+        assert!(
+            err.starts_with("LXCfile.toml:8:1: unknown field `cmnd`, expected one of "),
+            "{}",
+            err
+        );
+
+        let err = parse(&format!("name = \"web\"\n{}\n[[copy]]\nhost = \"app\"\ncontainer = \"/app\"\narchive = \"yes\"\n", image));
+        assert_eq!(
+            err,
+            "LXCfile.toml:10:11: invalid type: string \"yes\", expected a boolean"
+        );
+
+        let err = parse(image);
+        assert_eq!(err, "LXCfile.toml:1:1: missing field `name`");
+    }
+
+    #[test]
+    fn build_args_fill_every_string_field() {
+        let parse = || {
+            LxcFile::parse(
+                Path::new("LXCfile.toml"),
+                r#"
+name = "${APP}-${ENV}"
+
+[args]
+APP = "web"
+ENV = "dev"
+RELEASE = "3.19"
+DATA = "/srv/data"
+
+[image]
+distro = "alpine"
+release = "${RELEASE}"
+arch = "amd64"
+
+[limits]
+memory_max = "${MEMORY}"
+
+[[shared]]
+host = "${DATA}/${APP}"
+container = "mnt/${APP}"
+
+[[run]]
+cmd = "echo $HOME $${PATH} ${ENV}"
+
+[[run]]
+cmd = ["touch", "/etc/${APP}.conf"]
+"#,
+            )
+            .unwrap()
+        };
+
+        let err = parse().interpolate(&[]).unwrap_err();
+        assert_eq!(err, "limits.memory_max: undefined build argument `MEMORY`");
+
+        let err = parse()
+            .interpolate(&[("MEMORY".to_string(), "256M".to_string())])
+            .unwrap_err();
+        assert_eq!(err, "build argument `MEMORY` is not declared in [args]");
+
+        let mut file = parse();
+        file.args.insert("MEMORY".to_string(), String::new());
+        file.interpolate(&[
+            ("ENV".to_string(), "prod".to_string()),
+            ("MEMORY".to_string(), "256M".to_string()),
+        ])
+        .unwrap();
+
+        assert_eq!(file.name, "web-prod");
+        assert_eq!(file.image.release, "3.19");
+        assert_eq!(file.limits["memory_max"].to_string(), "256M");
+        assert_eq!(file.shared[0].host, "/srv/data/web");
+        assert_eq!(file.shared[0].container, "mnt/web");
+        assert_eq!(file.run[0].cmd.to_string(), "echo $HOME ${PATH} prod");
+        assert_eq!(file.run[1].cmd.argv(), ["touch", "/etc/web.conf"]);
+    }
+
+    #[test]
+    fn from_merges_inherited_files_and_built_containers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let lxcpath = tmp.path().join("lxc");
+        std::fs::create_dir_all(lxcpath.join("base")).unwrap();
+        std::fs::create_dir_all(tmp.path().join("app")).unwrap();
+        std::fs::write(
+            lxcpath.join("base").join(BUILT_FROM),
+            r#"
+name = "base"
+
+[image]
+distro = "alpine"
+release = "3.19"
+arch = "amd64"
+
+[limits]
+memory_max = "256M"
+pids_max = 100
+
+[[run]]
+cmd = "apk add curl"
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            tmp.path().join("common.toml"),
+            r#"
+from = "base"
+
+[[shared]]
+host = "/srv/logs"
+container = "var/log/app"
+
+[[run]]
+cmd = "adduser -D app"
+"#,
+        )
+        .unwrap();
+        let app = tmp.path().join("app/LXCfile.toml");
+        std::fs::write(
+            &app,
+            r#"
+from = "../common.toml"
+name = "app"
+
+[image]
+release = "3.20"
+
+[limits]
+memory_max = "1G"
+
+[[run]]
+cmd = "make"
+"#,
+        )
+        .unwrap();
+
+        let file = LxcFile::load(&app, &[], &lxcpath).unwrap();
+        assert_eq!(file.name, "app");
+        assert_eq!(
+            (file.image.distro.as_str(), file.image.release.as_str()),
+            ("alpine", "3.20")
+        );
+        assert_eq!(file.limits["memory_max"].to_string(), "1G");
+        assert_eq!(file.limits["pids_max"].to_string(), "100");
+        assert_eq!(file.shared[0].host, "/srv/logs");
+        let runs: Vec<String> = file.run.iter().map(|run| run.cmd.to_string()).collect();
+        assert_eq!(runs, ["apk add curl", "adduser -D app", "make"]);
+
+        std::fs::write(
+            tmp.path().join("common.toml"),
+            "from = \"app/LXCfile.toml\"\n",
+        )
+        .unwrap();
+        let err = LxcFile::resolve(&app, &lxcpath).unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("app/LXCfile.toml: inherits from itself"));
+    }
+
+    #[test]
+    fn inherited_paths_are_relative_to_their_own_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path().join("base");
+        let api = tmp.path().join("api");
+        std::fs::create_dir_all(&base).unwrap();
+        std::fs::create_dir_all(&api).unwrap();
+        std::fs::write(base.join("app.conf"), "").unwrap();
+        std::fs::write(api.join("api.conf"), "").unwrap();
+        std::fs::write(
+            base.join("LXCfile.toml"),
+            r#"
+name = "base"
+
+[image]
+distro = "alpine"
+release = "3.19"
+arch = "amd64"
+
+[[copy]]
+host = "app.conf"
+container = "/etc/app.conf"
+
+[[shared]]
+host = "data"
+container = "srv/data"
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            api.join("LXCfile.toml"),
+            r#"
+from = "../base/LXCfile.toml"
+name = "api"
+
+[[copy]]
+host = "api.conf"
+container = "/etc/api.conf"
+"#,
+        )
+        .unwrap();
+
+        let file = LxcFile::load(&api.join("LXCfile.toml"), &[], tmp.path()).unwrap();
+        let hosts: Vec<&str> = file.copy.iter().map(|copy| copy.host.as_str()).collect();
+        let base = base.canonicalize().unwrap();
+        assert_eq!(hosts, [base.join("app.conf").to_str().unwrap(), "api.conf"]);
+        assert_eq!(file.shared[0].host, base.join("data").to_str().unwrap());
+        assert_eq!(crate::check::check(&file, &api), []);
+    }
+
+    #[test]
+    fn inherited_interpolated_paths_are_not_rebased() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path().join("base");
+        let api = tmp.path().join("api");
+        std::fs::create_dir_all(&base).unwrap();
+        std::fs::create_dir_all(&api).unwrap();
+        std::fs::write(
+            base.join("LXCfile.toml"),
+            r#"
+name = "base"
+
+[image]
+distro = "alpine"
+release = "3.19"
+arch = "amd64"
+
+[args]
+DATA = "/srv/data"
+TEMPLATES = "/srv/templates"
+
+[[shared]]
+host = "${DATA}"
+container = "srv/data"
+
+[[template]]
+source = "${TEMPLATES}/app.conf"
+container = "/etc/app.conf"
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            api.join("LXCfile.toml"),
+            "from = \"../base/LXCfile.toml\"\nname = \"api\"\n",
+        )
+        .unwrap();
+
+        let file = LxcFile::load(&api.join("LXCfile.toml"), &[], tmp.path()).unwrap();
+        assert_eq!(file.shared[0].host, "/srv/data");
+        assert_eq!(file.template[0].source, "/srv/templates/app.conf");
+    }
+}
@@ -12,10 +12,32 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod accounts;
+mod buildlog;
+mod cache;
+mod check;
+mod command;
+mod context;
+mod error;
+mod init;
+mod inspect;
+mod lxcfile;
 mod manage;
-
-use clap::{Args, Parser, Subcommand};
-use std::{process::{Command, Stdio}};
+mod output;
+mod runtime;
+mod settings;
+
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use context::Context;
+use inspect::OutputFormat;
+use output::{ColorChoice, Output, Verbosity};
+#[cfg(feature = "liblxc")]
+use runtime::LibLxc;
+#[cfg(not(feature = "liblxc"))]
+use runtime::LxcTools;
+use runtime::Runtime;
+use settings::Settings;
+use std::process::ExitCode;
 
 #[derive(Debug, Parser)] // requires `derive` feature
 #[command(
@@ -48,6 +70,32 @@ struct CmtCli {
     #[arg(short, long, help = "Don't show progress information", global = true)]
     quiet: bool,
 
+    #[arg(
+        long,
+        help = "Also show every command cmt runs",
+        conflicts_with = "quiet",
+        global = true
+    )]
+    verbose: bool,
+
+    #[arg(
+        long,
+        help = "Show everything --verbose does and how cmt got there",
+        conflicts_with = "quiet",
+        global = true
+    )]
+    debug: bool,
+
+    #[arg(
+        long,
+        value_name = "WHEN",
+        value_enum,
+        default_value_t = ColorChoice::Auto,
+        help = "Color the progress information",
+        global = true
+    )]
+    color: ColorChoice,
+
     #[arg(
         short = 'P',
         long,
@@ -56,6 +104,13 @@ struct CmtCli {
         global = true
     )]
     lxcpath: Option<String>,
+
+    #[arg(
+        long,
+        help = "Print the commands and file changes instead of running them",
+        global = true
+    )]
+    dry_run: bool,
 }
 
 #[derive(Debug, Subcommand)]
@@ -103,7 +158,7 @@ struct CreateArgs {
         short,
         long,
         value_name = "DIR",
-        help = "Place rootfs directory under DIR [default: /var/lib/lxc/<container-name>/rootfs]"
+        help = "Place rootfs directory under DIR [default: <lxcpath>/<container-name>/rootfs]"
     )]
     dir: Option<String>,
 
@@ -111,7 +166,7 @@ struct CreateArgs {
     network: Option<String>,
 }
 
-#[derive(Debug, Args)]
+#[derive(Debug, Default, Args)]
 #[command(
     version,
     about,
@@ -132,7 +187,7 @@ struct DeleteArgs {
     rcfile: Option<String>,
 }
 
-#[derive(Debug, Args)]
+#[derive(Debug, Default, Args)]
 #[command(
     version,
     about,
@@ -197,15 +252,17 @@ struct ExecuteArgs {
     #[arg(
         short = 'v',
         long,
+        value_name = "VAR=VALUE",
         help = "Set an additional variable that is seen by the attached program in the container. May be specified multiple times."
     )]
-    set_var: bool,
+    set_var: Vec<String>,
 
     #[arg(
         long,
-        help = "Keep an additional environment variable. Only applicable if --clear-env is specified. May be used"
+        value_name = "VAR",
+        help = "Keep an additional environment variable. Only applicable if --clear-env is specified. May be specified multiple times."
     )]
-    keep_var: bool,
+    keep_var: Vec<String>,
 
     #[arg(
         short = 'f',
@@ -240,7 +297,7 @@ struct ExecuteArgs {
     context: Option<String>,
 }
 
-#[derive(Debug, Args)]
+#[derive(Debug, Default, Args)]
 #[command(
     version,
     about,
@@ -332,7 +389,7 @@ struct StartArgs {
     share_pid: Option<String>,
 }
 
-#[derive(Debug, Args)]
+#[derive(Debug, Default, Args)]
 #[command(
     version,
     about,
@@ -381,7 +438,7 @@ struct StopArgs {
     rcfile: Option<String>,
 }
 
-#[derive(Debug, Args)]
+#[derive(Debug, Clone, Default, Args)]
 #[command(
     version,
     about,
@@ -441,6 +498,13 @@ struct ListArgs {
         help = "Comma separated list of groups a container must have to be displayed"
     )]
     groups: Option<Vec<String>>,
+
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        help = "Print name, state, PID, IPs, memory, groups and autostart of each container as FORMAT"
+    )]
+    output: Option<OutputFormat>,
 }
 
 #[derive(Debug, Args)]
@@ -497,6 +561,13 @@ struct ConfigArgs {
         help = "Show configuration variable KEY from running container"
     )]
     config: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        help = "Print the container's status, or the item read, as FORMAT"
+    )]
+    output: Option<OutputFormat>,
 }
 
 #[derive(Debug, Args)]
@@ -516,76 +587,152 @@ struct BuildArgs {
         help = "Name of the Dockerfile"
     )]
     file: Option<String>,
-}
 
-fn main() {
-    match CmtCli::try_parse() {
-        Ok(cli) => {
-            // Command's global flags
-            let mut global_options: String = String::new();
+    #[arg(
+        long,
+        help = "Only validate the LXCfile and report every problem found"
+    )]
+    check: bool,
 
-            if cli.quiet {
-                unsafe { manage::STDOUT = false };
-                unsafe { manage::STDERR = false };
-            }
+    #[arg(
+        long,
+        value_name = "KEY=VAL",
+        value_parser = parse_build_arg,
+        help = "Set build argument KEY, declared in the LXCfile's [args], to VAL. May be specified multiple times."
+    )]
+    build_arg: Vec<(String, String)>,
 
-            if cli.logfile.is_some() {
-                global_options.push_str(&format!(" --logfile={}", cli.logfile.unwrap()));
-            }
+    #[arg(
+        long,
+        help = "Run every step again instead of resuming from cached snapshots"
+    )]
+    no_cache: bool,
 
-            if cli.logpriority.is_some() {
-                global_options.push_str(&format!(" --logpriority={}", cli.logpriority.unwrap()));
-            }
+    #[arg(
+        long,
+        help = "Leave the containers of a failed build in place for debugging instead of removing them"
+    )]
+    keep_failed: bool,
 
-            if cli.lxcpath.is_some() {
-                global_options.push_str(&format!(" --lxcpath={}", cli.lxcpath.unwrap()));
-            }
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Name the container NAME instead of the LXCfile's name"
+    )]
+    name: Option<String>,
+
+    #[arg(
+        long,
+        conflicts_with = "update",
+        help = "Destroy the containers the build would create, if they exist, before building. \
+                If the build then fails, no container is left"
+    )]
+    replace: bool,
+
+    #[arg(
+        long,
+        help = "Apply only the copy, shared, run and limits sections to the existing container"
+    )]
+    update: bool,
+
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Build NAME-1 and copy it into NAME-2 to NAME-N, overriding the LXCfile's replicas"
+    )]
+    replicas: Option<u32>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write the time and full output of every build step to FILE"
+    )]
+    build_log: Option<String>,
 
-            // Build command based on subcommands.
-            let mut cmdstr: Vec<String> = Vec::new();
-            match cli.sub {
-                Some(Subcommands::Create(args)) => cmdstr = manage::create(args),
-                Some(Subcommands::Delete(args)) => cmdstr = manage::delete(args),
-                Some(Subcommands::Execute(args)) => cmdstr = manage::execute(args),
-                Some(Subcommands::Start(args)) => cmdstr = manage::start(args),
-                Some(Subcommands::Stop(args)) => cmdstr = manage::stop(args),
-                Some(Subcommands::List(args)) => cmdstr = manage::list(args),
-                Some(Subcommands::Copy(args)) => cmdstr = manage::copy(args),
-                Some(Subcommands::Config(args)) => cmdstr = manage::config(args),
-                Some(Subcommands::Build(args)) => cmdstr = manage::build(args),
-                _ => {}
+    #[arg(
+        long,
+        help = "Print the LXCfile merged with every file it inherits from, then exit"
+    )]
+    print_resolved: bool,
+}
+
+fn parse_build_arg(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err("expected KEY=VAL".to_string()),
+    }
+}
+
+fn main() -> ExitCode {
+    // Settings files and CMT_* variables become the options' defaults
+    let settings = match Settings::load() {
+        Ok(settings) => settings,
+        Err(e) => return fail(&Output::default(), e),
+    };
+    let parsed = settings
+        .apply(CmtCli::command())
+        .try_get_matches()
+        .and_then(|matches| CmtCli::from_arg_matches(&matches));
+
+    match parsed {
+        Ok(cli) => {
+            let verbosity = if cli.quiet {
+                Verbosity::Quiet
+            } else if cli.debug {
+                Verbosity::Debug
+            } else if cli.verbose {
+                Verbosity::Verbose
+            } else {
+                Verbosity::Normal
             };
+            let out = Output::new(verbosity, cli.color);
 
-            for cmd in cmdstr {
-                let mut command_and_args: Vec<&str> = cmd.split_whitespace().collect();
+            // Command's global flags
+            let ctx = Context {
+                lxcpath: cli.lxcpath,
+                logfile: cli.logfile,
+                logpriority: cli.logpriority,
+                dry_run: cli.dry_run,
+                output: out.clone(),
+            };
 
-                // Quiet mode redirects everything to /dev/null
-                let mut stdout = Stdio::inherit();
-                let mut stderr = Stdio::inherit();
-                if cli.quiet {
-                    stdout = Stdio::null();
-                    stderr = Stdio::null();
+            // Run subcommands against liblxc when built in, else the lxc tools
+            #[cfg(feature = "liblxc")]
+            let mut runtime = LibLxc::new(ctx);
+            #[cfg(not(feature = "liblxc"))]
+            let mut runtime = LxcTools::new(ctx);
+            let rt: &mut dyn Runtime = &mut runtime;
+            let result = match cli.sub {
+                Some(Subcommands::Create(args)) => manage::create(rt, args),
+                Some(Subcommands::Delete(args)) => manage::delete(rt, args),
+                // `exec` exits with the status of the command it ran
+                Some(Subcommands::Execute(args)) => {
+                    return match manage::execute(rt, args) {
+                        Ok(status) => ExitCode::from(error::exit_code(status)),
+                        Err(e) => fail(&out, e),
+                    };
                 }
+                Some(Subcommands::Start(args)) => manage::start(rt, args),
+                Some(Subcommands::Stop(args)) => manage::stop(rt, args),
+                Some(Subcommands::List(args)) => manage::list(rt, args),
+                Some(Subcommands::Copy(args)) => manage::copy(rt, args),
+                Some(Subcommands::Config(args)) => manage::config(rt, args),
+                Some(Subcommands::Build(args)) => manage::build(rt, args),
+                _ => Ok(()),
+            };
 
-                match Command::new(command_and_args[0])
-                    .args(command_and_args.split_off(1))
-                    .stdout(stdout)
-                    .stderr(stderr)
-                    .spawn()
-                {
-                    Ok(mut shell) => {
-                        let _ = shell.wait();
-                    }
-                    Err(e) => {
-                        println!("{:?}", e);
-                    }
-                }
+            match result {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => fail(&out, e),
             }
-
-            
-        }
-        Err(e) => {
-            _ = e.print();
         }
+        Err(e) => e.exit(),
     }
 }
+
+// Errors are reported even in quiet mode
+fn fail(out: &Output, e: error::Error) -> ExitCode {
+    out.error(&e);
+    ExitCode::from(e.exit_code())
+}
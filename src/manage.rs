@@ -1,5 +1,3 @@
-use std::os::unix::fs::PermissionsExt;
-use std::process::{Command, Stdio};
 // Copyright 2024 David Araújo
 //
 // Licensed under the Apache License, Version 2.0 (the "License");
@@ -14,616 +12,2663 @@ use std::process::{Command, Stdio};
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::io::prelude::*;
-use std::{fs::OpenOptions, path::Path, vec};
-
-use subprocess::Exec;
-
-use toml::Table;
-
+use std::collections::BTreeMap;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+
+use crate::accounts;
+use crate::buildlog::BuildLog;
+use crate::cache;
+use crate::check;
+use crate::command::{quote, Cmd};
+use crate::context::Context;
+use crate::error::{Error, Result};
+use crate::init::{self, InitSystem};
+use crate::inspect::{self, ConfigItem};
+use crate::lxcfile::{
+    self, Copy, Device, Image, LimitValue, LxcFile, Network, Restart, Run, RunCmd, Shared,
+    Template, BUILT_FROM,
+};
+use crate::output::{Event, Verbosity};
+use crate::runtime::Runtime;
 use crate::{
     BuildArgs, ConfigArgs, CopyArgs, CreateArgs, DeleteArgs, ExecuteArgs, ListArgs, StartArgs,
     StopArgs,
 };
 
-pub static mut STDOUT: bool = true;
-pub static mut STDERR: bool = true;
+pub fn create(rt: &mut dyn Runtime, args: CreateArgs) -> Result<()> {
+    rt.create(&args)
+}
 
-pub fn create(args: CreateArgs) -> Vec<String> {
-    let mut create_options: String = String::new();
-    if args.config.is_some() && !args.config.as_ref().unwrap().is_empty() {
-        create_options.push_str(&format!(" --config={}", args.config.unwrap()));
-    }
+pub fn delete(rt: &mut dyn Runtime, args: DeleteArgs) -> Result<()> {
+    rt.destroy(&args)
+}
 
-    if args.dir.is_some() && !args.dir.as_ref().unwrap().is_empty() {
-        if !Path::new(args.dir.clone().unwrap().as_str()).exists() {
-            _ = std::fs::create_dir(args.dir.clone().unwrap().as_str());
-        }
-        create_options.push_str(&format!(" --dir={}", args.dir.unwrap().as_str()));
-    }
+pub fn execute(rt: &mut dyn Runtime, args: ExecuteArgs) -> Result<ExitStatus> {
+    rt.attach(&args)
+}
 
-    if args.network.is_some() && !args.network.as_ref().unwrap().is_empty() {
-        create_options.push_str(&format!(" --network={}", args.network.unwrap()));
-    }
+pub fn start(rt: &mut dyn Runtime, args: StartArgs) -> Result<()> {
+    rt.start(&args)
+}
 
-    // Parse template
-    let image: Vec<&str> = args.image.split(':').collect();
+pub fn stop(rt: &mut dyn Runtime, args: StopArgs) -> Result<()> {
+    rt.stop(&args)
+}
 
-    let cmdstr = format!(
-        "lxc-create --name={}{} --template=download -- --dist={} --release={} --arch={}",
-        args.name, create_options, image[0], image[1], image[2],
-    );
+pub fn list(rt: &mut dyn Runtime, args: ListArgs) -> Result<()> {
+    let Some(format) = args.output else {
+        let output = rt.list(&args)?;
+        if rt.context().output.shows(Verbosity::Normal) {
+            print!("{}", output);
+        }
+        return Ok(());
+    };
 
-    vec![cmdstr]
+    // Ask for one name per line, whatever display options were given
+    let names = rt.list(&ListArgs {
+        line: true,
+        fancy: false,
+        fancy_format: None,
+        ..args
+    })?;
+    let containers = names
+        .split_whitespace()
+        .map(|name| rt.inspect(name))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Structured output is what the caller asked for, even with --quiet
+    print!("{}", inspect::render_all(format, &containers)?);
+    Ok(())
 }
 
-pub fn delete(args: DeleteArgs) -> Vec<String> {
-    let mut delete_options: String = String::new();
+pub fn copy(rt: &mut dyn Runtime, args: CopyArgs) -> Result<()> {
+    // Get source and destination locations
+    let source_path = resolve_location(rt, &args.source)?;
+    let destination_path = resolve_location(rt, &args.destination)?;
+    copy_host(
+        rt,
+        CopyArgs {
+            source: source_path,
+            destination: destination_path,
+            ..args
+        },
+    )
+}
 
-    if args.force {
-        delete_options.push_str(&format!(" --force"));
-    }
+// Copies between two host paths, taken as they are
+fn copy_host(rt: &mut dyn Runtime, args: CopyArgs) -> Result<()> {
+    let mut copy_options: Vec<String> = vec!["--recursive".to_string()];
 
-    if args.snapshots {
-        delete_options.push_str(&format!(" --snapshots"));
+    if args.follow_link {
+        copy_options.push("--dereference".to_string());
     }
 
-    if args.rcfile.is_some() {
-        delete_options.push_str(&format!(" --rcfile={}", args.rcfile.unwrap()));
+    if args.archive {
+        copy_options.push("--archive".to_string());
     }
 
-    let cmdstr = format!("lxc-destroy --name={}{}", args.name, delete_options,);
-
-    vec![cmdstr]
+    // Copy recursively and follows symbolic links
+    let cmd = Cmd::new("cp")
+        .args(copy_options)
+        .arg(args.source)
+        .arg(args.destination);
+    rt.context().run(&cmd)
 }
 
-pub fn execute(args: ExecuteArgs) -> Vec<String> {
-    let mut execute_options = String::new();
-
-    if let Some(elevated_privileges) = args.elevated_privileges {
-        execute_options.push_str(&format!(" --elevated-privileges={}", elevated_privileges));
+// Turns `[CONTAINER:]PATH` into a host path, prefixing
+// container paths with the container's rootfs
+fn resolve_location(rt: &mut dyn Runtime, location: &str) -> Result<String> {
+    match location.split_once(':') {
+        Some((container, path)) if !container.is_empty() => {
+            Ok(format!("{}{}", rootfs_path(rt, container)?, path))
+        }
+        _ => Ok(location.to_string()),
     }
+}
 
-    if let Some(arch) = args.arch {
-        execute_options.push_str(&format!(" --arch={}", arch));
+// Find rootfs path from `lxc.rootfs.path = [dir:]/path/to/rootfs`
+fn rootfs_path(rt: &mut dyn Runtime, container: &str) -> Result<String> {
+    let value = rt.info(container, Some("lxc.rootfs.path"))?;
+    if value.is_empty() {
+        return Err(Error::Runtime(format!(
+            "no rootfs found for container {}",
+            container
+        )));
     }
+    Ok(value.strip_prefix("dir:").unwrap_or(&value).to_string())
+}
 
-    if let Some(namespaces) = args.namespaces {
-        execute_options.push_str(&format!(" --namespaces={}", namespaces));
-    }
+pub fn config(rt: &mut dyn Runtime, args: ConfigArgs) -> Result<()> {
+    let key = match &args.state_object {
+        Some(state_object) => Some(state_object[0].as_str()),
+        None => args.config.as_deref().filter(|config| !config.is_empty()),
+    };
 
-    if let Some(remount_sys_proc) = args.remount_sys_proc {
-        execute_options.push_str(&format!(" --remount-sys-proc={}", remount_sys_proc));
+    if let (Some(format), None) = (args.output, key) {
+        print!("{}", inspect::render_one(format, &rt.inspect(&args.name)?)?);
+        return Ok(());
     }
 
-    if args.clear_env {
-        execute_options.push_str(" --clear-env");
-    }
+    let output = if let Some(state_object) = &args.state_object {
+        rt.cgroup(
+            &args.name,
+            &state_object[0],
+            state_object.get(1).map(String::as_str),
+        )?
+    } else {
+        rt.info(&args.name, key)?
+    };
 
-    if args.keep_env {
-        execute_options.push_str(" --keep-env");
+    // Setting a cgroup item has nothing to report
+    let setting = args.state_object.as_ref().is_some_and(|so| so.len() > 1);
+    match (args.output, key) {
+        (Some(format), Some(key)) if !setting => {
+            let item = ConfigItem {
+                name: args.name.clone(),
+                key: key.to_string(),
+                value: output.trim().to_string(),
+            };
+            print!("{}", inspect::render_one(format, &item)?);
+        }
+        _ if rt.context().output.shows(Verbosity::Normal) && !output.is_empty() => {
+            println!("{}", output.trim_end());
+        }
+        _ => {}
     }
+    Ok(())
+}
 
-    if let Some(pty_log) = args.pty_log {
-        execute_options.push_str(&format!(" --pty-log={}", pty_log));
+pub fn build(rt: &mut dyn Runtime, args: BuildArgs) -> Result<()> {
+    // Parse build file
+    let context = Path::new(args.path.as_deref().unwrap_or("."));
+    let lxcfilepath = context.join(args.file.as_deref().unwrap_or("LXCfile.toml"));
+
+    let out = rt.context().output.clone();
+    let lxcpath = rt.context().lxcpath().to_path_buf();
+    // Shown as merged, even when it would not build
+    let mut resolved = resolved(&lxcfilepath, &lxcpath, &args.build_arg)?;
+    if args.print_resolved {
+        print!("{}", to_toml(&lxcfilepath, &resolved)?);
+        return Ok(());
     }
-
-    if args.set_var {
-        execute_options.push_str(" --set-var");
+    let mut container_build_file = LxcFile::load(&lxcfilepath, &args.build_arg, &lxcpath)?;
+    if let Some(name) = &args.name {
+        container_build_file.name = name.clone();
     }
-
-    if args.keep_var {
-        execute_options.push_str(" --keep-var");
+    if args.replicas.is_some() {
+        container_build_file.replicas = args.replicas;
     }
-
-    if let Some(rcfile) = args.rcfile {
-        execute_options.push_str(&format!(" --rcfile={}", rcfile));
+    out.emit(Event::Debug(format!("read {}", lxcfilepath.display())));
+
+    // Refuse to touch anything unless the whole file makes sense
+    let problems = check::check(&container_build_file, context);
+    for problem in &problems {
+        out.emit(Event::Problem(&format_args!(
+            "{}: {}",
+            lxcfilepath.display(),
+            problem
+        )));
     }
-
-    if let Some(uid) = args.uid {
-        execute_options.push_str(&format!(" --uid={}", uid));
+    if !problems.is_empty() {
+        return Err(Error::Invalid(format!(
+            "{} has {} problem{}",
+            lxcfilepath.display(),
+            problems.len(),
+            if problems.len() == 1 { "" } else { "s" }
+        )));
+    }
+    if args.check {
+        out.emit(Event::Done(&format!("{} is valid", lxcfilepath.display())));
+        return Ok(());
     }
 
-    if let Some(gid) = args.gid {
-        execute_options.push_str(&format!(" --gid={}", gid));
+    // The first replica is built, the others are copies of it that only
+    // differ by the volumes that have the replica's number in them
+    let replicas = container_build_file.replicas;
+    let (replica_shared, shared) = container_build_file
+        .shared
+        .drain(..)
+        .partition(|shared: &Shared| shared.host.contains(lxcfile::REPLICA));
+    container_build_file.shared = shared;
+    let base_name = container_build_file.name.clone();
+    match replicas {
+        Some(_) => container_build_file.name = format!("{}-1", base_name),
+        None if !replica_shared.is_empty() => {
+            return Err(Error::Invalid(format!(
+                "{}: {} is only known when building replicas",
+                lxcfilepath.display(),
+                lxcfile::REPLICA
+            )))
+        }
+        None => {}
     }
 
-    if let Some(context) = args.context {
-        execute_options.push_str(&format!(" --context={}", context));
+    // Snapshots cannot be taken of a dry run, and lxc-snapshot
+    // restores copies into the default place only
+    let cache = !args.no_cache
+        && !args.update
+        && !rt.context().dry_run
+        && container_build_file.image.dir.is_none()
+        && container_build_file
+            .stage
+            .iter()
+            .all(|stage| stage.image.dir.is_none());
+    out.emit(Event::Debug(format!(
+        "build cache {}",
+        if cache { "enabled" } else { "disabled" }
+    )));
+
+    // Every container this build ends up with
+    let targets: Vec<String> = match replicas {
+        Some(replicas) => (1..=replicas)
+            .map(|replica| format!("{}-{}", base_name, replica))
+            .collect(),
+        None => vec![container_build_file.name.clone()],
+    };
+    if args.replace {
+        replace(rt, &container_build_file, &targets)?;
     }
 
-    let cmdstr = format!(
-        "lxc-attach --name={} {} -- {}",
-        args.name,
-        execute_options,
-        args.command.join(" ").as_str()
+    // Commands are only kept when there is a log to write them to
+    let mut log = BuildLog::new();
+    if args.build_log.is_some() {
+        out.keep_transcript();
+    }
+    let mut stages = Vec::new();
+    let mut created = false;
+    let mut clones = Vec::new();
+    let staged = build_stages(
+        rt,
+        &container_build_file,
+        context,
+        cache,
+        &mut stages,
+        &mut log,
     );
+    let result = if args.update {
+        staged.and_then(|()| {
+            for (replica, name) in (1..).zip(&targets) {
+                let shared: Vec<Shared> = container_build_file
+                    .shared
+                    .iter()
+                    .chain(&replica_shared)
+                    .map(|location| for_replica(location, replica))
+                    .collect();
+                update_container(
+                    rt,
+                    &container_build_file,
+                    name,
+                    &shared,
+                    context,
+                    &stages,
+                    &mut log,
+                )?;
+            }
+            Ok(())
+        })
+    } else {
+        staged
+            .and_then(|()| {
+                build_container(
+                    rt,
+                    &container_build_file,
+                    context,
+                    cache,
+                    &stages,
+                    &mut created,
+                    &mut log,
+                )
+            })
+            .and_then(|()| match replicas {
+                Some(replicas) => replicate(
+                    rt,
+                    &base_name,
+                    replicas,
+                    &replica_shared,
+                    context,
+                    &mut clones,
+                    &mut log,
+                ),
+                None => Ok(()),
+            })
+    };
+    let result = match result {
+        Ok(()) => {
+            // For other LXCfiles to inherit from, any replica will do
+            let saved = std::path::absolute(context)
+                .map_err(Error::io(context))
+                .and_then(|context| {
+                    // Read away from the build context
+                    lxcfile::rebase(&mut resolved, &context);
+                    to_toml(&lxcfilepath, &resolved)
+                })
+                .and_then(|resolved| {
+                    targets.iter().try_for_each(|target| {
+                        let built_from = rt.context().container_dir(target).join(BUILT_FROM);
+                        rt.context().write_file(&built_from, &resolved, 0o644)
+                    })
+                });
+            saved.and(remove_stages(rt, &container_build_file, &stages))
+        }
+        Err(e) => {
+            // Whatever got created is left half-configured
+            let mut partial: Vec<String> = container_build_file
+                .stage
+                .iter()
+                .take(stages.len())
+                .map(|stage| container_build_file.stage_container(stage))
+                .collect();
+            if created {
+                partial.push(container_build_file.name.clone());
+            }
+            partial.extend(clones);
+            roll_back(rt, &partial, args.keep_failed);
+            Err(e)
+        }
+    };
 
-    vec![cmdstr]
+    if out.shows(Verbosity::Normal) {
+        print!("{}", log.summary());
+    }
+    match &args.build_log {
+        // The build's own error matters more than the log's
+        Some(path) => result.and(log.write(rt.context(), Path::new(path))),
+        None => result,
+    }
 }
 
-pub fn start(args: StartArgs) -> Vec<String> {
-    let mut start_options = String::new();
-
-    if args.daemon {
-        start_options.push_str(" --daemon");
+// Destroys the containers a failed build left, or only says which ones
+// when they are kept
+fn roll_back(rt: &mut dyn Runtime, partial: &[String], keep: bool) {
+    let out = rt.context().output.clone();
+    if partial.is_empty() {
+        return;
     }
-
-    if args.foreground {
-        start_options.push_str(" --foreground");
+    if keep {
+        out.emit(Event::Stage(&format!(
+            "Build failed, keeping {} for debugging",
+            partial.join(", ")
+        )));
+        return;
     }
 
-    if let Some(pidfile) = args.pidfile {
-        start_options.push_str(&format!(" --pidfile={}", pidfile));
+    out.emit(Event::Stage("Build failed, rolling back..."));
+    let mut removed = true;
+    for name in partial.iter().rev() {
+        // Keep going, and report the step that failed rather than this
+        let destroyed = rt.destroy(&DeleteArgs {
+            name: name.clone(),
+            force: true,
+            ..Default::default()
+        });
+        if let Err(destroy_error) = destroyed {
+            out.emit(Event::Stage(&format!(
+                "Could not remove {}: {}",
+                name, destroy_error
+            )));
+            removed = false;
+        }
     }
+    if removed {
+        out.emit(Event::Done(&format!("Removed {}", partial.join(", "))));
+    }
+}
 
-    if let Some(rcfile) = args.rcfile {
-        start_options.push_str(&format!(" --rcfile={}", rcfile));
+// Copies `BASE-1` into `BASE-2` to `BASE-N`, recording each in `clones`,
+// then mounts the volumes of every replica and starts it
+fn replicate(
+    rt: &mut dyn Runtime,
+    base: &str,
+    replicas: u32,
+    shared: &[Shared],
+    context: &Path,
+    clones: &mut Vec<String>,
+    log: &mut BuildLog,
+) -> Result<()> {
+    let out = rt.context().output.clone();
+    out.emit(Event::Stage(&format!("Creating {} replicas...", replicas)));
+    let first = format!("{}-1", base);
+    log.step(&out, "stop", || {
+        stop(
+            rt,
+            StopArgs {
+                name: first.clone(),
+                ..Default::default()
+            },
+        )
+    })?;
+
+    for replica in 2..=replicas {
+        let name = format!("{}-{}", base, replica);
+        out.emit(Event::Step(&format!("{} -> {}", first, name)));
+        log.step(&out, format!("replica[{}]", replica), || {
+            rt.clone_container(&first, &name)
+        })?;
+        clones.push(name);
     }
 
-    if let Some(console) = args.console {
-        start_options.push_str(&format!(" --console={}", console));
+    // Each replica's volumes are mounted once all of them are copied
+    for replica in 1..=replicas {
+        let name = format!("{}-{}", base, replica);
+        // Not left to lxc-copy, as NAME-1 keeps whatever the image set
+        log.step(&out, format!("replica[{}].hostname", replica), || {
+            rt.set_config(&name, "lxc.uts.name", &name)
+        })?;
+        let shared: Vec<Shared> = shared
+            .iter()
+            .map(|location| for_replica(location, replica))
+            .collect();
+        if !shared.is_empty() {
+            log.step(&out, format!("replica[{}].shared", replica), || {
+                mount_shared(rt, &name, &shared, context)
+            })?;
+        }
+        log.step(&out, format!("replica[{}].start", replica), || {
+            rt.start(&StartArgs {
+                name,
+                ..Default::default()
+            })
+        })?;
     }
 
-    if let Some(console_log) = args.console_log {
-        start_options.push_str(&format!(" --console-log={}", console_log));
+    out.emit(Event::Done(&format!(
+        "{}-1 to {}-{} running",
+        base, base, replicas
+    )));
+    Ok(())
+}
+
+// `location` with `${REPLICA}` replaced by the number of `replica`
+fn for_replica(location: &Shared, replica: u32) -> Shared {
+    Shared {
+        host: location
+            .host
+            .replace(lxcfile::REPLICA, &replica.to_string()),
+        container: location.container.clone(),
     }
+}
 
-    if args.close_all_fds {
-        start_options.push_str(" --close-all-fds");
+// Destroys whichever of `targets` and the stage containers of `file`
+// already exist, so the build can create them again
+fn replace(rt: &mut dyn Runtime, file: &LxcFile, targets: &[String]) -> Result<()> {
+    let existing = rt.list(&ListArgs {
+        line: true,
+        ..Default::default()
+    })?;
+    let existing: Vec<&str> = existing.split_whitespace().collect();
+    let stages = file.stage.iter().map(|stage| file.stage_container(stage));
+    let replaced: Vec<String> = targets
+        .iter()
+        .cloned()
+        .chain(stages)
+        .filter(|name| existing.contains(&name.as_str()))
+        .collect();
+    if replaced.is_empty() {
+        return Ok(());
     }
 
-    if let Some(define) = args.define {
-        start_options.push_str(&format!(" --define={}", define));
+    let out = rt.context().output.clone();
+    out.emit(Event::Stage(&format!(
+        "Replacing {}...",
+        replaced.join(", ")
+    )));
+    for name in &replaced {
+        rt.destroy(&DeleteArgs {
+            name: name.clone(),
+            force: true,
+            ..Default::default()
+        })?;
     }
+    out.emit(Event::Done(&format!("Removed {}", replaced.join(", "))));
+    Ok(())
+}
 
-    if let Some(share_net) = args.share_net {
-        start_options.push_str(&format!(" --share-net={}", share_net));
+// Applies the `[[copy]]`, `[[shared]]`, `[[run]]` and `[limits]` of `file`
+// to the existing container `name`, with `shared` for its volumes. Those
+// it already mounts are left alone rather than mounted twice.
+fn update_container(
+    rt: &mut dyn Runtime,
+    file: &LxcFile,
+    name: &str,
+    shared: &[Shared],
+    context: &Path,
+    stages: &[(String, String)],
+    log: &mut BuildLog,
+) -> Result<()> {
+    let out = rt.context().output.clone();
+    out.emit(Event::Stage(&format!("Updating {}...", name)));
+    let rootfs = rootfs_path(rt, name)?;
+    if rt.inspect(name)?.state == "RUNNING" {
+        log.step(&out, "stop", || {
+            rt.stop(&StopArgs {
+                name: name.to_string(),
+                ..Default::default()
+            })
+        })?;
     }
 
-    if let Some(share_ipc) = args.share_ipc {
-        start_options.push_str(&format!(" --share-ipc={}", share_ipc));
+    if !file.copy.is_empty() {
+        for (i, copy) in file.copy.iter().enumerate() {
+            log.step(&out, format!("copy[{}]", i + 1), || {
+                copy_into(rt, copy, context, stages, &rootfs)
+            })?;
+        }
+        out.emit(Event::Done("Content copied to the container"));
     }
 
-    if let Some(share_uts) = args.share_uts {
-        start_options.push_str(&format!(" --share-uts={}", share_uts));
+    let mounted = rt.info(name, Some("lxc.mount.entry"))?;
+    let mut unmounted = Vec::new();
+    for location in shared {
+        let entry = mount_entry(&shared_host(context, &location.host)?, &location.container);
+        if !mounted.lines().any(|line| line.trim() == entry) {
+            unmounted.push(Shared {
+                host: location.host.clone(),
+                container: location.container.clone(),
+            });
+        }
+    }
+    if !unmounted.is_empty() {
+        log.step(&out, "shared", || {
+            mount_shared(rt, name, &unmounted, context)
+        })?;
+        out.emit(Event::Done("Shared volumes mounted"));
     }
 
-    if let Some(share_pid) = args.share_pid {
-        start_options.push_str(&format!(" --share-pid={}", share_pid));
+    log.step(&out, "start", || {
+        rt.start(&StartArgs {
+            name: name.to_string(),
+            ..Default::default()
+        })
+    })?;
+
+    out.emit(Event::Stage("Running commands..."));
+    let env = file.env_vars();
+    for (i, run) in file.run.iter().enumerate() {
+        log.step(&out, format!("run[{}]", i + 1), || {
+            run_step(rt, name, &rootfs, run, &env)
+        })?;
     }
 
-    let cmdstr = format!("lxc-start --name={}{}", args.name, start_options);
+    apply_limits(rt, name, &file.limits, log)?;
+    log.step(&out, "restart", || restart(rt, name))?;
 
-    vec![cmdstr]
+    out.emit(Event::Done("Container updated"));
+    Ok(())
 }
 
-pub fn stop(args: StopArgs) -> Vec<String> {
-    let mut stop_options = String::new();
-
-    if args.reboot {
-        stop_options.push_str(" --reboot");
+// The LXCfile at `path` merged with what it inherits, with the build
+// arguments given as the defaults of `[args]` so it builds the same again
+fn resolved(path: &Path, lxcpath: &Path, build_args: &[(String, String)]) -> Result<toml::Table> {
+    let mut table = LxcFile::resolve(path, lxcpath)?;
+    if let Some(toml::Value::Table(args)) = table.get_mut("args") {
+        for (key, value) in build_args {
+            args.insert(key.clone(), toml::Value::String(value.clone()));
+        }
     }
+    Ok(table)
+}
 
-    if args.nowait {
-        stop_options.push_str(" --nowait");
-    }
+fn to_toml(path: &Path, table: &toml::Table) -> Result<String> {
+    toml::to_string(table).map_err(|e| Error::Invalid(format!("{}: {}", path.display(), e)))
+}
 
-    if let Some(timeout) = args.timeout {
-        stop_options.push_str(&format!(" --timeout={}", timeout));
+// Builds every `[[stage]]` in order, recording the rootfs of each
+fn build_stages(
+    rt: &mut dyn Runtime,
+    file: &LxcFile,
+    context: &Path,
+    cache: bool,
+    stages: &mut Vec<(String, String)>,
+    log: &mut BuildLog,
+) -> Result<()> {
+    let out = rt.context().output.clone();
+    for (i, stage) in file.stage.iter().enumerate() {
+        out.emit(Event::Stage(&format!("Building stage {}...", stage.name)));
+        let container_name = file.stage_container(stage);
+        let prefix = format!("stage[{}].", i + 1);
+
+        if cache {
+            let steps: Vec<(String, Step)> =
+                stage
+                    .copy
+                    .iter()
+                    .enumerate()
+                    .map(|(j, copy)| (format!("{}copy[{}]", prefix, j + 1), Step::Copy(copy)))
+                    .chain(
+                        stage.run.iter().enumerate().map(|(j, run)| {
+                            (format!("{}run[{}]", prefix, j + 1), Step::Run(run, &[]))
+                        }),
+                    )
+                    .collect();
+            let rootfs = build_cached(
+                rt,
+                &container_name,
+                &prefix,
+                &stage.image,
+                &steps,
+                context,
+                stages,
+                log,
+            )?;
+            stages.push((stage.name.clone(), rootfs));
+        } else {
+            let rootfs = log.step(&out, format!("{}image", prefix), || {
+                create_from_image(rt, &container_name, &stage.image)
+            })?;
+            stages.push((stage.name.clone(), rootfs.clone()));
+
+            log.step(&out, format!("{}start", prefix), || {
+                rt.start(&StartArgs {
+                    name: container_name.clone(),
+                    ..Default::default()
+                })
+            })?;
+            for (j, copy) in stage.copy.iter().enumerate() {
+                log.step(&out, format!("{}copy[{}]", prefix, j + 1), || {
+                    copy_into(rt, copy, context, stages, &rootfs)
+                })?;
+            }
+            for (j, run) in stage.run.iter().enumerate() {
+                log.step(&out, format!("{}run[{}]", prefix, j + 1), || {
+                    run_step(rt, &container_name, &rootfs, run, &[])
+                })?;
+            }
+        }
+        out.emit(Event::Done(&format!("Stage {} built", stage.name)));
     }
+    Ok(())
+}
 
-    if args.kill {
-        stop_options.push_str(" --kill");
+// Destroys the containers of the stages that got created
+fn remove_stages(rt: &mut dyn Runtime, file: &LxcFile, stages: &[(String, String)]) -> Result<()> {
+    if stages.is_empty() {
+        return Ok(());
     }
 
-    if args.nolock {
-        stop_options.push_str(" --nolock");
+    let mut result = Ok(());
+    for stage in file.stage.iter().take(stages.len()).rev() {
+        // Keep going, a leftover container is better than several
+        let destroyed = rt.destroy(&DeleteArgs {
+            name: file.stage_container(stage),
+            force: true,
+            ..Default::default()
+        });
+        result = result.and(destroyed);
     }
-
-    if args.nokill {
-        stop_options.push_str(" --nokill");
+    if result.is_ok() {
+        rt.context()
+            .output
+            .emit(Event::Done("Intermediate containers removed"));
     }
+    result
+}
 
-    if let Some(rcfile) = args.rcfile {
-        stop_options.push_str(&format!(" --rcfile={}", rcfile));
-    }
+fn build_container(
+    rt: &mut dyn Runtime,
+    container_build_file: &LxcFile,
+    context: &Path,
+    cache: bool,
+    stages: &[(String, String)],
+    created: &mut bool,
+    log: &mut BuildLog,
+) -> Result<()> {
+    let out = rt.context().output.clone();
+    let container_name = container_build_file.name.clone();
+    let env = container_build_file.env_vars();
+    let vars = container_build_file.template_vars();
+
+    if cache {
+        // Directories on the host are not part of any snapshot
+        for location in &container_build_file.shared {
+            let host = shared_host(context, &location.host)?;
+            rt.context().create_dir_all(&host)?;
+        }
+
+        let shared = &container_build_file.shared;
+        let network = &container_build_file.network;
+        let devices = &container_build_file.device;
+        let restart = container_build_file.restart;
+        let steps: Vec<(String, Step)> = (!env.is_empty())
+            .then(|| ("env".to_string(), Step::Env(&env)))
+            .into_iter()
+            .chain((!network.is_empty()).then(|| ("network".to_string(), Step::Network(network))))
+            .chain(
+                container_build_file
+                    .entrypoint
+                    .as_deref()
+                    .map(|entrypoint| {
+                        (
+                            "entrypoint".to_string(),
+                            Step::Entrypoint(entrypoint, restart, &env),
+                        )
+                    }),
+            )
+            .chain(
+                container_build_file
+                    .copy
+                    .iter()
+                    .enumerate()
+                    .map(|(i, copy)| (format!("copy[{}]", i + 1), Step::Copy(copy))),
+            )
+            .chain(
+                container_build_file
+                    .template
+                    .iter()
+                    .enumerate()
+                    .map(|(i, template)| {
+                        (
+                            format!("template[{}]", i + 1),
+                            Step::Template(template, &vars),
+                        )
+                    }),
+            )
+            .chain((!shared.is_empty()).then(|| ("shared".to_string(), Step::Shared(shared))))
+            .chain((!devices.is_empty()).then(|| ("device".to_string(), Step::Devices(devices))))
+            .chain(
+                container_build_file
+                    .run
+                    .iter()
+                    .enumerate()
+                    .map(|(i, run)| (format!("run[{}]", i + 1), Step::Run(run, &env))),
+            )
+            .collect();
+        build_cached(
+            rt,
+            &container_name,
+            "",
+            &container_build_file.image,
+            &steps,
+            context,
+            stages,
+            log,
+        )?;
+        *created = true;
+        out.emit(Event::Done("Container created"));
+
+        log.step(&out, "start", || {
+            rt.start(&StartArgs {
+                name: container_name.clone(),
+                ..Default::default()
+            })
+        })?;
+        out.emit(Event::Done("Container started"));
+    } else {
+        // Create container
+        let rootfs = log.step(&out, "image", || {
+            create_from_image(rt, &container_name, &container_build_file.image)
+        })?;
+        *created = true;
+        out.emit(Event::Done("Container created"));
+
+        log.step(&out, "env", || set_env(rt, &container_name, &env))?;
+
+        if !container_build_file.network.is_empty() {
+            log.step(&out, "network", || {
+                set_network(rt, &container_name, &container_build_file.network)
+            })?;
+        }
 
-    let cmdstr = format!("lxc-stop --name={}{}", args.name, stop_options);
+        if let Some(entrypoint) = &container_build_file.entrypoint {
+            let restart = container_build_file.restart;
+            log.step(&out, "entrypoint", || {
+                install_entrypoint(rt, &rootfs, entrypoint, restart, &env)
+            })?;
+        }
 
-    vec![cmdstr]
-}
+        log.step(&out, "start", || {
+            rt.start(&StartArgs {
+                name: container_name.clone(),
+                ..Default::default()
+            })
+        })?;
+        out.emit(Event::Done("Container started"));
+
+        // Handle copies. In this case, the destination is always the container
+        if !container_build_file.copy.is_empty() {
+            for (i, copy) in container_build_file.copy.iter().enumerate() {
+                log.step(&out, format!("copy[{}]", i + 1), || {
+                    copy_into(rt, copy, context, stages, &rootfs)
+                })?;
+            }
+            out.emit(Event::Done("Content copied to the container"));
+        }
 
-pub fn list(args: ListArgs) -> Vec<String> {
-    let mut list_options = String::new();
+        if !container_build_file.template.is_empty() {
+            for (i, template) in container_build_file.template.iter().enumerate() {
+                log.step(&out, format!("template[{}]", i + 1), || {
+                    render_template(rt, template, &vars, context, &rootfs)
+                })?;
+            }
+            out.emit(Event::Done("Templates rendered into the container"));
+        }
 
-    if args.line {
-        list_options.push_str(" --line");
-    }
+        // Handle shared volume
+        if !container_build_file.shared.is_empty() {
+            log.step(&out, "shared", || {
+                mount_shared(rt, &container_name, &container_build_file.shared, context)
+            })?;
+            out.emit(Event::Done("Shared volumes mounted"));
+        }
+
+        if !container_build_file.device.is_empty() {
+            log.step(&out, "device", || {
+                pass_devices(rt, &container_name, &container_build_file.device)
+            })?;
+            out.emit(Event::Done("Devices passed through"));
+        }
 
-    if args.fancy {
-        list_options.push_str(" --fancy");
+        log.step(&out, "restart", || restart(rt, &container_name))?;
+
+        out.emit(Event::Stage("Running commands..."));
+        for (i, run) in container_build_file.run.iter().enumerate() {
+            log.step(&out, format!("run[{}]", i + 1), || {
+                run_step(rt, &container_name, &rootfs, run, &env)
+            })?;
+        }
     }
 
-    if let Some(fancy_format) = args.fancy_format {
-        list_options.push_str(&format!(" --fancy-format={}", fancy_format.join(",")));
+    apply_limits(rt, &container_name, &container_build_file.limits, log)?;
+    log.step(&out, "restart", || restart(rt, &container_name))?;
+
+    out.emit(Event::Done("Container built"));
+    Ok(())
+}
+
+// Applies limitation to system resources via `lxc-cgroups`
+fn apply_limits(
+    rt: &mut dyn Runtime,
+    name: &str,
+    limits: &BTreeMap<String, LimitValue>,
+    log: &mut BuildLog,
+) -> Result<()> {
+    let out = rt.context().output.clone();
+    for (key, value) in limits {
+        out.emit(Event::Step(&format!("{} = {}", key, value)));
+        log.step(&out, format!("limits.{}", key), || {
+            config(
+                rt,
+                ConfigArgs {
+                    name: name.to_string(),
+                    state_object: Some(vec![key.replace('_', "."), value.to_string()]),
+                    config: Some(String::default()),
+                    output: None,
+                },
+            )
+        })?;
     }
 
-    if args.active {
-        list_options.push_str(" --active");
+    if !limits.is_empty() {
+        out.emit(Event::Done("Resource limits applied"));
     }
+    Ok(())
+}
+
+/// A step of building a container that is snapshotted once done.
+#[derive(Debug)]
+enum Step<'a> {
+    /// `[env]` as `KEY=VALUE` items, set in the container's configuration
+    Env(&'a [String]),
+    Network(&'a [Network]),
+    /// Also given the `[env]` items, and the same for `Run`
+    Entrypoint(&'a str, Restart, &'a [String]),
+    Copy(&'a Copy),
+    /// Rendered with the values of `LxcFile::template_vars`
+    Template(&'a Template, &'a BTreeMap<String, String>),
+    /// Every shared volume, as they are a single restart apart
+    Shared(&'a [Shared]),
+    Devices(&'a [Device]),
+    Run(&'a Run, &'a [String]),
+}
 
-    if args.running {
-        list_options.push_str(" --running");
+// Builds `name` in its cache container `NAME-cache`, which keeps a
+// snapshot after each step. Steps are skipped for as long as their key
+// matches the snapshot's, then the container is restored from the last
+// matching one and the build resumes. Steps come with their place in the
+// LXCfile, after `prefix`. Returns the rootfs of `name`.
+#[allow(clippy::too_many_arguments)]
+fn build_cached(
+    rt: &mut dyn Runtime,
+    name: &str,
+    prefix: &str,
+    image: &Image,
+    steps: &[(String, Step)],
+    context: &Path,
+    stages: &[(String, String)],
+    log: &mut BuildLog,
+) -> Result<String> {
+    let out = rt.context().output.clone();
+    let cache_name = format!("{}-cache", name);
+
+    // Creating the container counts as the first step
+    let mut keys = vec![cache::key("", &describe_image(image))];
+    for (_, step) in steps {
+        let key = cache::key(&keys[keys.len() - 1], &describe(step, context, stages)?);
+        keys.push(key);
     }
 
-    if args.frozen {
-        list_options.push_str(" --frozen");
+    let mut index = cache::Index::load(&rt.context().container_dir(&cache_name))?;
+    let cached = index.matching(&keys);
+    if cached == 0 {
+        let existing = rt.list(&ListArgs {
+            line: true,
+            ..Default::default()
+        })?;
+        if existing.split_whitespace().any(|name| name == cache_name) {
+            rt.destroy(&DeleteArgs {
+                name: cache_name.clone(),
+                snapshots: true,
+                force: true,
+                ..Default::default()
+            })?;
+        }
+        index.entries.clear();
+
+        let snapshot = log.step(&out, format!("{}image", prefix), || {
+            create_from_image(rt, &cache_name, image)?;
+            rt.snapshot(&cache_name)
+        })?;
+        index.push(keys[0].clone(), snapshot)?;
+    } else {
+        log.step(&out, format!("{}cache", prefix), || {
+            for snapshot in index.truncate(cached)?.iter().rev() {
+                rt.destroy_snapshot(&cache_name, snapshot)?;
+            }
+            let (_, snapshot) = &index.entries[cached - 1];
+            rt.restore(&cache_name, snapshot, None)?;
+            // Restoring replaces the container's directory, index included
+            index.save()
+        })?;
+        out.emit(Event::Done(&format!(
+            "Using the cache for {} of {} steps",
+            cached,
+            keys.len()
+        )));
+        log.cached(format!("{}image", prefix));
+        for (field, _) in &steps[..cached - 1] {
+            log.cached(field.clone());
+        }
     }
 
-    if args.stopped {
-        list_options.push_str(" --stopped");
+    resume_steps(
+        rt,
+        &cache_name,
+        steps,
+        &keys,
+        &mut index,
+        context,
+        stages,
+        log,
+    )?;
+
+    let (_, snapshot) = &index.entries[index.entries.len() - 1];
+    log.step(&out, format!("{}restore", prefix), || {
+        rt.restore(&cache_name, snapshot, Some(name))?;
+        rootfs_path(rt, name)
+    })
+}
+
+// Performs the steps the cache container has no snapshot for yet,
+// snapshotting it after each one under its key
+#[allow(clippy::too_many_arguments)]
+fn resume_steps(
+    rt: &mut dyn Runtime,
+    cache_name: &str,
+    steps: &[(String, Step)],
+    keys: &[String],
+    index: &mut cache::Index,
+    context: &Path,
+    stages: &[(String, String)],
+    log: &mut BuildLog,
+) -> Result<()> {
+    let out = rt.context().output.clone();
+    let rootfs = rootfs_path(rt, cache_name)?;
+    // The first key is the container's creation, not one of the steps
+    let done = index.entries.len();
+    for ((field, step), key) in steps[done - 1..].iter().zip(&keys[done..]) {
+        let snapshot = log.step(&out, field.clone(), || {
+            match step {
+                Step::Env(env) => set_env(rt, cache_name, env),
+                Step::Network(network) => set_network(rt, cache_name, network),
+                Step::Entrypoint(entrypoint, restart, env) => {
+                    install_entrypoint(rt, &rootfs, entrypoint, *restart, env)
+                }
+                Step::Copy(copy) => copy_into(rt, copy, context, stages, &rootfs),
+                Step::Template(template, vars) => {
+                    render_template(rt, template, vars, context, &rootfs)
+                }
+                Step::Shared(shared) => mount_shared(rt, cache_name, shared, context),
+                Step::Devices(devices) => pass_devices(rt, cache_name, devices),
+                Step::Run(run, env) => start(
+                    rt,
+                    StartArgs {
+                        name: cache_name.to_string(),
+                        ..Default::default()
+                    },
+                )
+                .and_then(|()| {
+                    let result = run_step(rt, cache_name, &rootfs, run, env);
+                    // lxc-snapshot only copies stopped containers, and the
+                    // next build restores a stopped one after a failure
+                    let stopped = stop(
+                        rt,
+                        StopArgs {
+                            name: cache_name.to_string(),
+                            ..Default::default()
+                        },
+                    );
+                    result.and(stopped)
+                }),
+            }?;
+
+            let snapshot = rt.snapshot(cache_name)?;
+            out.emit(Event::Debug(format!(
+                "step {} saved as snapshot {} of {}",
+                &key[..12],
+                snapshot,
+                cache_name
+            )));
+            Ok(snapshot)
+        })?;
+        index.push(key.clone(), snapshot)?;
     }
+    Ok(())
+}
 
-    if args.defined {
-        list_options.push_str(" --defined");
+// What the key of `step` is made of: each field as written in the
+// LXCfile, and for copies and templates a checksum of what is copied
+fn describe(step: &Step, context: &Path, stages: &[(String, String)]) -> Result<String> {
+    let mut key = Fields::default();
+    match step {
+        Step::Env(env) => {
+            key.add("step", "env");
+            key.all("env", env.iter());
+        }
+        Step::Network(network) => {
+            key.add("step", "network");
+            for (i, interface) in network.iter().enumerate() {
+                for (name, value) in interface.config() {
+                    key.add(&format!("net.{}.{}", i, name), &value);
+                }
+            }
+        }
+        Step::Entrypoint(entrypoint, restart, env) => {
+            key.add("step", "entrypoint");
+            key.add("entrypoint", entrypoint);
+            let restart = match restart {
+                Restart::No => "no",
+                Restart::Always => "always",
+            };
+            key.add("restart", restart);
+            key.all("env", env.iter());
+        }
+        Step::Copy(copy) => {
+            key.add("step", "copy");
+            key.maybe("from", &copy.from);
+            key.add("host", &copy.host);
+            key.add("container", &copy.container);
+            key.add("archive", &copy.archive.to_string());
+            key.add("follow_link", &copy.follow_link.to_string());
+            let source = copy_source(copy, context, stages)?;
+            key.add(
+                "checksum",
+                &cache::checksum(Path::new(&source), copy.follow_link)?,
+            );
+        }
+        Step::Template(template, vars) => {
+            key.add("step", "template");
+            key.add("source", &template.source);
+            key.add("container", &template.container);
+            key.maybe("mode", &template.mode);
+            key.maybe("owner", &template.owner);
+            for (name, value) in vars.iter() {
+                key.add(&format!("vars.{}", name), value);
+            }
+            let source = context.join(&template.source);
+            key.add("checksum", &cache::checksum(&source, true)?);
+        }
+        Step::Shared(shared) => {
+            key.add("step", "shared");
+            // Relative hosts depend on where the build context is
+            key.add("context", &shared_host(context, ".")?.display().to_string());
+            for location in shared.iter() {
+                key.add("host", &location.host);
+                key.add("container", &location.container);
+            }
+        }
+        Step::Devices(devices) => {
+            key.add("step", "device");
+            for device in devices.iter() {
+                key.add("path", &device.path);
+                key.maybe("container", &device.container);
+                key.add("access", device.access());
+            }
+        }
+        Step::Run(run, env) => {
+            key.add("step", "run");
+            match &run.cmd {
+                RunCmd::Shell(line) => key.add("shell", line),
+                RunCmd::Exec(argv) => key.all("exec", argv.iter()),
+            }
+            key.maybe("user", &run.user);
+            key.maybe("group", &run.group);
+            key.maybe("workdir", &run.workdir);
+            key.all("env", env.iter());
+        }
     }
+    Ok(key.0)
+}
+
+// What the key of creating a container from `image` is made of
+fn describe_image(image: &Image) -> String {
+    let mut key = Fields::default();
+    key.add("step", "image");
+    key.add("distro", &image.distro);
+    key.add("release", &image.release);
+    key.add("arch", &image.arch);
+    key.maybe("config", &image.config);
+    key.maybe("dir", &image.dir);
+    key.maybe("network", &image.network);
+    key.0
+}
 
-    if let Some(nesting) = args.nesting {
-        list_options.push_str(&format!(" --nesting={}", nesting));
+/// `name=value` items, each ended by a NUL so that no two lists of
+/// fields read the same. Unset fields are left out.
+#[derive(Default)]
+struct Fields(String);
+
+impl Fields {
+    fn add(&mut self, name: &str, value: &str) {
+        self.0.push_str(name);
+        self.0.push('=');
+        self.0.push_str(value);
+        self.0.push('\0');
     }
 
-    if let Some(filter) = args.filter {
-        list_options.push_str(&format!(" --filter={}", filter));
+    fn maybe(&mut self, name: &str, value: &Option<String>) {
+        if let Some(value) = value {
+            self.add(name, value);
+        }
     }
 
-    if let Some(groups) = args.groups {
-        list_options.push_str(&format!(" --groups={}", groups.join(",")));
+    fn all<'a>(&mut self, name: &str, values: impl Iterator<Item = &'a String>) {
+        for value in values {
+            self.add(name, value);
+        }
     }
+}
+
+// Creates `name` from `image` and returns the path of its rootfs
+fn create_from_image(rt: &mut dyn Runtime, name: &str, image: &Image) -> Result<String> {
+    create(
+        rt,
+        CreateArgs {
+            name: name.to_string(),
+            image: format!("{}:{}:{}", image.distro, image.release, image.arch),
+            config: image.config.clone(),
+            dir: image.dir.clone(),
+            network: image.network.clone(),
+        },
+    )?;
+
+    // A dry run has no container to ask, so expect the rootfs where
+    // lxc-create would have put it
+    let rootfs = if !rt.context().dry_run {
+        match rootfs_path(rt, name) {
+            Ok(rootfs) => rootfs,
+            Err(e) => {
+                // The caller only rolls back containers it got a rootfs for
+                let _ = rt.destroy(&DeleteArgs {
+                    name: name.to_string(),
+                    force: true,
+                    ..Default::default()
+                });
+                return Err(e);
+            }
+        }
+    } else if let Some(dir) = image.dir.as_deref().filter(|dir| !dir.is_empty()) {
+        dir.to_string()
+    } else {
+        let rootfs = rt.context().default_rootfs(name);
+        rootfs.display().to_string()
+    };
+    rt.context()
+        .output
+        .emit(Event::Debug(format!("rootfs of {} is {}", name, rootfs)));
+    Ok(rootfs)
+}
 
-    let cmdstr = format!("lxc-ls{}", list_options);
+// Writes the entrypoint to a script and has the container's init system
+// start it at boot
+fn install_entrypoint(
+    rt: &mut dyn Runtime,
+    rootfs: &str,
+    entrypoint: &str,
+    restart: Restart,
+    env: &[String],
+) -> Result<()> {
+    let ctx = rt.context();
+    let rootfs = Path::new(rootfs);
+    let script = init::resolve(rootfs, init::ENTRYPOINT);
+    if let Some(dir) = script.parent() {
+        ctx.create_dir_all(dir)?;
+    }
+    // Init systems hand services an environment of their own
+    let exports: String = env
+        .iter()
+        .map(|var| format!("export {}\n", quote(var)))
+        .collect();
+    ctx.write_file(
+        &script,
+        &format!("#!/bin/sh\n{}{}\n", exports, entrypoint),
+        0o755,
+    )?;
+
+    match InitSystem::detect(rootfs) {
+        Some(init) => {
+            init.install(ctx, rootfs, restart)?;
+            ctx.output.emit(Event::Done(&format!(
+                "Entrypoint installed as a {} service",
+                init
+            )));
+            Ok(())
+        }
+        // A dry run has no rootfs to look at
+        None if ctx.dry_run => {
+            ctx.output.emit(Event::Debug(
+                "init system unknown until the rootfs exists".to_string(),
+            ));
+            Ok(())
+        }
+        None => Err(Error::Runtime(format!(
+            "no supported init system (systemd, OpenRC, BusyBox init, SysVinit) in {}",
+            rootfs.display()
+        ))),
+    }
+}
 
-    vec![cmdstr]
+// Copies `copy_elem` into `rootfs`, from the build context or from the
+// rootfs of one of the `stages` built so far
+fn copy_into(
+    rt: &mut dyn Runtime,
+    copy_elem: &Copy,
+    context: &Path,
+    stages: &[(String, String)],
+    rootfs: &str,
+) -> Result<()> {
+    let label = match &copy_elem.from {
+        Some(from) => format!("{}:{}", from, copy_elem.host),
+        None => copy_elem.host.clone(),
+    };
+    rt.context().output.emit(Event::Step(&format!(
+        "{} -> {}",
+        label, copy_elem.container
+    )));
+    // Host paths already, which may well contain a `:`
+    copy_host(
+        rt,
+        CopyArgs {
+            source: copy_source(copy_elem, context, stages)?,
+            destination: format!("{}{}", rootfs, copy_elem.container.trim_matches('.')),
+            archive: copy_elem.archive,
+            follow_link: copy_elem.follow_link,
+        },
+    )
 }
 
-pub fn copy(args: CopyArgs) -> Vec<String> {
-    let mut copy_options: String = String::from("--recursive");
+// Writes `template` from the build context into `rootfs` with `${NAME}`
+// replaced by the value in `vars`
+fn render_template(
+    rt: &mut dyn Runtime,
+    template: &Template,
+    vars: &BTreeMap<String, String>,
+    context: &Path,
+    rootfs: &str,
+) -> Result<()> {
+    let ctx = rt.context();
+    ctx.output.emit(Event::Step(&format!(
+        "{} -> {}",
+        template.source, template.container
+    )));
+    let source = context.join(&template.source);
+    let contents = std::fs::read_to_string(&source).map_err(Error::io(&source))?;
+    let rendered = lxcfile::substitute(&contents, vars, "template variable")
+        .map_err(|e| Error::Invalid(format!("{}: {}", source.display(), e)))?;
+
+    let rootfs = Path::new(rootfs);
+    let path = init::resolve(rootfs, &template.container);
+    if let Some(parent) = path.parent() {
+        ctx.create_dir_all(parent)?;
+    }
+    ctx.write_file(&path, &rendered, template.mode().map_err(Error::Invalid)?)?;
 
-    // Get source location
-    let mut source_path = String::new();
-    let source_location: Vec<&str> = args.source.split(':').collect();
-    if args.source.contains(':') && source_location.len() > 1 {
-        // Find rootfs path
-        source_path = (Exec::shell(&format!(
-            "lxc-info --name={} --config=lxc.rootfs.path",
-            source_location[0]
-        )) | Exec::shell("cut -c 19-"))
-        .capture()
-        .unwrap()
-        .stdout_str()
-        .trim()
-        .to_string();
-        source_path.push_str(source_location[1]);
-    } else {
-        source_path.push_str(source_location[0]);
-    }
-
-    // Get destination location
-    let mut destination_path = String::new();
-    let destination_location: Vec<&str> = args.destination.split(':').collect();
-    if args.destination.contains(':') && destination_location.len() > 1 {
-        // Find rootfs path
-        destination_path = (Exec::shell(&format!(
-            "lxc-info --name={} --config=lxc.rootfs.path",
-            destination_location[0]
-        )) | Exec::shell("cut -c 19-"))
-        .capture()
-        .unwrap()
-        .stdout_str()
-        .trim()
-        .to_string();
-        destination_path.push_str(destination_location[1]);
-    } else {
-        destination_path.push_str(destination_location[0]);
+    let Some(owner) = &template.owner else {
+        return Ok(());
+    };
+    // A dry run has no rootfs to look the names up in
+    if ctx.dry_run {
+        ctx.output.emit(Event::Debug(format!(
+            "owner {} unknown until the rootfs exists",
+            owner
+        )));
+        return Ok(());
+    }
+    let (user, group) = match owner.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (owner.as_str(), None),
+    };
+    let (uid, mut gid) = accounts::user(rootfs, user)?;
+    if let Some(group) = group {
+        gid = accounts::group(rootfs, group)?;
     }
+    ctx.chown(&path, uid, gid)
+}
 
-    if args.follow_link {
-        copy_options.push_str(" --dereference");
+// Host path of what `copy_elem` copies
+fn copy_source(copy_elem: &Copy, context: &Path, stages: &[(String, String)]) -> Result<String> {
+    match &copy_elem.from {
+        Some(from) => {
+            let (_, stage_rootfs) = stages
+                .iter()
+                .find(|(stage, _)| stage == from)
+                .ok_or_else(|| Error::Invalid(format!("no stage named {}", from)))?;
+            Ok(format!("{}{}", stage_rootfs, copy_elem.host))
+        }
+        None => Ok(context.join(&copy_elem.host).display().to_string()),
     }
+}
 
-    if args.archive {
-        copy_options.push_str(" --archive");
+// Bind mounts each host directory into the container, creating it on
+// the host first
+fn mount_shared(rt: &mut dyn Runtime, name: &str, shared: &[Shared], context: &Path) -> Result<()> {
+    for location in shared {
+        rt.context().output.emit(Event::Step(&format!(
+            "{} -> {}",
+            location.host, location.container
+        )));
+        // Creates mount directory at the host, if it does not exist already
+        let host = shared_host(context, &location.host)?;
+        rt.context().create_dir_all(&host)?;
+
+        // Edits container configuration file and enables volume mounting
+        rt.set_config(
+            name,
+            "lxc.mount.entry",
+            &mount_entry(&host, &location.container),
+        )?;
     }
+    Ok(())
+}
 
-    // Copy recursively and follows symbolic links
-    let cmdstr = format!("cp {} {} {}", copy_options, source_path, destination_path);
+fn mount_entry(host: &Path, container: &str) -> String {
+    format!("{} {} none bind,create=dir 0 0", host.display(), container)
+}
 
-    vec![cmdstr]
+// Allows each device in the devices cgroup of `name` and bind mounts it
+// at the same place in the container, unless told otherwise
+fn pass_devices(rt: &mut dyn Runtime, name: &str, devices: &[Device]) -> Result<()> {
+    let allow = devices_allow_key();
+    for device in devices {
+        let container = device.container.as_deref().unwrap_or(&device.path);
+        rt.context()
+            .output
+            .emit(Event::Step(&format!("{} -> {}", device.path, container)));
+
+        let metadata = std::fs::metadata(&device.path).map_err(Error::io(&device.path))?;
+        let kind = if metadata.file_type().is_char_device() {
+            'c'
+        } else if metadata.file_type().is_block_device() {
+            'b'
+        } else {
+            return Err(Error::Invalid(format!(
+                "{} is not a device node",
+                device.path
+            )));
+        };
+        let (major, minor) = device_numbers(metadata.rdev());
+        rt.set_config(
+            name,
+            allow,
+            &format!("{} {}:{} {}", kind, major, minor, device.access()),
+        )?;
+        rt.set_config(
+            name,
+            "lxc.mount.entry",
+            &format!(
+                "{} {} none bind,optional,create=file 0 0",
+                device.path,
+                container.trim_start_matches('/')
+            ),
+        )?;
+    }
+    Ok(())
 }
 
-pub fn config(args: ConfigArgs) -> Vec<String> {
-    let mut cmdstr: String = String::new();
+// The unified hierarchy has its own key for the devices controller
+fn devices_allow_key() -> &'static str {
+    if Path::new("/sys/fs/cgroup/cgroup.controllers").exists() {
+        "lxc.cgroup2.devices.allow"
+    } else {
+        "lxc.cgroup.devices.allow"
+    }
+}
 
-    let mut config_options: String = String::new();
+// Major and minor numbers of a `st_rdev`, as glibc's `major` and `minor`
+fn device_numbers(rdev: u64) -> (u64, u64) {
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & 0xffff_ff00);
+    (major, minor)
+}
 
-    if let Some(state_object) = args.state_object {
-        cmdstr.push_str(&format!("lxc-cgroup --name={}", args.name));
+// Relative shared paths live in the build context
+fn shared_host(context: &Path, host: &str) -> Result<PathBuf> {
+    let host = context.join(host);
+    std::path::absolute(&host).map_err(Error::io(&host))
+}
 
-        config_options.push_str(&format!(" {}", state_object[0]));
-        if state_object.len() > 1 {
-            config_options.push_str(&format!(" {}", state_object[1]));
+// Replaces the interfaces in the configuration of `name` with `network`
+fn set_network(rt: &mut dyn Runtime, name: &str, network: &[Network]) -> Result<()> {
+    // An empty `lxc.net` drops the interfaces set before it
+    rt.set_config(name, "lxc.net", "")?;
+    for (i, interface) in network.iter().enumerate() {
+        rt.context()
+            .output
+            .emit(Event::Step(&format!("lxc.net.{}: {}", i, interface.kind)));
+        for (key, value) in interface.config() {
+            rt.set_config(name, &format!("lxc.net.{}.{}", i, key), &value)?;
         }
-    } else {
-        cmdstr.push_str(&format!("lxc-info --name={}", args.name));
+    }
+    Ok(())
+}
 
-        if let Some(config) = args.config {
-            config_options.push_str(&format!(" --config={}", config));
-        }
+// Sets each `KEY=VALUE` of `env` in the container's configuration
+fn set_env(rt: &mut dyn Runtime, name: &str, env: &[String]) -> Result<()> {
+    for var in env {
+        rt.set_config(name, "lxc.environment", var)?;
     }
+    Ok(())
+}
 
-    cmdstr.push_str(config_options.as_str());
+// Runs `step` in `name` with the variables of `env`
+fn run_step(
+    rt: &mut dyn Runtime,
+    name: &str,
+    rootfs: &str,
+    step: &Run,
+    env: &[String],
+) -> Result<()> {
+    rt.context().output.emit(Event::Step(&step.cmd));
+    let (uid, gid) = run_as(rt.context(), Path::new(rootfs), step)?;
+    let mut argv = step.cmd.argv();
+    // lxc-attach always starts in /, so change directory first
+    if let Some(workdir) = &step.workdir {
+        argv.splice(
+            0..0,
+            [
+                "/bin/sh",
+                "-c",
+                "cd \"$1\" && shift && exec \"$@\"",
+                "sh",
+                workdir,
+            ]
+            .map(str::to_string),
+        );
+    }
+    let status = rt.attach(&ExecuteArgs {
+        name: name.to_string(),
+        command: argv,
+        uid,
+        gid,
+        set_var: env.to_vec(),
+        ..Default::default()
+    })?;
+    if !status.success() {
+        return Err(Error::Failed {
+            cmd: step.cmd.to_string(),
+            status,
+        });
+    }
+    Ok(())
+}
 
-    vec![cmdstr]
+// The uid and gid to run `step` as, from the names in the rootfs. A dry
+// run has no rootfs yet and shows the names as written.
+fn run_as(ctx: &Context, rootfs: &Path, step: &Run) -> Result<(Option<String>, Option<String>)> {
+    if ctx.dry_run {
+        return Ok((step.user.clone(), step.group.clone()));
+    }
+    let (uid, mut gid) = match &step.user {
+        Some(user) => {
+            let (uid, gid) = accounts::user(rootfs, user)?;
+            (Some(uid), Some(gid))
+        }
+        None => (None, None),
+    };
+    if let Some(group) = &step.group {
+        gid = Some(accounts::group(rootfs, group)?);
+    }
+    Ok((
+        uid.map(|uid| uid.to_string()),
+        gid.map(|gid| gid.to_string()),
+    ))
 }
 
-pub fn build(args: BuildArgs) -> Vec<String> {
-    // Parse build file
-    let lxcfilepath = format!("{}/{}", args.path.unwrap(), args.file.unwrap());
+// Stops the container and starts it again
+fn restart(rt: &mut dyn Runtime, name: &str) -> Result<()> {
+    rt.stop(&StopArgs {
+        name: name.to_string(),
+        ..Default::default()
+    })?;
+    rt.start(&StartArgs {
+        name: name.to_string(),
+        ..Default::default()
+    })
+}
 
-    // Parse file
-    let contents = std::fs::read_to_string(lxcfilepath).expect("File not found");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Context;
+    use crate::inspect::ContainerInfo;
+    use crate::runtime::mock::{Call, MockRuntime};
+    use crate::runtime::LxcTools;
+
+    fn build_args(dir: &Path) -> BuildArgs {
+        BuildArgs {
+            path: Some(dir.display().to_string()),
+            file: Some("LXCfile.toml".to_string()),
+            check: false,
+            build_arg: Vec::new(),
+            no_cache: true,
+            keep_failed: false,
+            name: None,
+            replace: false,
+            update: false,
+            replicas: None,
+            build_log: None,
+            print_resolved: false,
+        }
+    }
 
-    // Create container
-    let container_build_file = contents.parse::<Table>().unwrap();
+    #[test]
+    fn build_runs_every_section_in_order() {
+        let tmp = tempfile::tempdir().unwrap();
+        let context = tmp.path().join("context");
+        std::fs::create_dir(&context).unwrap();
+        std::fs::write(context.join("app.conf"), "port = 80\n").unwrap();
+        let shared = tmp.path().join("shared");
+        std::fs::write(
+            context.join("LXCfile.toml"),
+            format!(
+                r#"
+name = "web"
+
+[image]
+distro = "alpine"
+release = "3.19"
+arch = "amd64"
+
+[limits]
+cpuset_cpus = "0,1"
+
+[[copy]]
+host = "{}"
+container = "/app.conf"
+
+[[shared]]
+host = "{}"
+container = "mnt/shared"
+
+[[run]]
+cmd = "echo 'a b'"
+"#,
+                context.join("app.conf").display(),
+                shared.display()
+            ),
+        )
+        .unwrap();
+
+        let mut rt = mock(&tmp.path().join("lxc"));
+        build(&mut rt, build_args(&context)).unwrap();
+
+        let rootfs = rt.container("web").rootfs.clone();
+        assert_eq!(
+            std::fs::read_to_string(rootfs.join("app.conf")).unwrap(),
+            "port = 80\n"
+        );
+        assert!(shared.is_dir());
+        assert_eq!(
+            rt.container("web").config,
+            vec![(
+                "lxc.mount.entry".to_string(),
+                format!("{} mnt/shared none bind,create=dir 0 0", shared.display())
+            )]
+        );
+        assert_eq!(rt.container("web").cgroup["cpuset.cpus"], "0,1");
+        assert!(rt.container("web").running);
+
+        let calls: Vec<&Call> = rt
+            .calls
+            .iter()
+            .filter(|call| !matches!(call, Call::Info(..)))
+            .collect();
+        let web = || "web".to_string();
+        assert_eq!(
+            calls,
+            vec![
+                &Call::Create(web()),
+                &Call::Start(web()),
+                &Call::SetConfig(
+                    web(),
+                    "lxc.mount.entry".to_string(),
+                    format!("{} mnt/shared none bind,create=dir 0 0", shared.display())
+                ),
+                &Call::Stop(web()),
+                &Call::Start(web()),
+                &Call::Attach(
+                    web(),
+                    vec!["/bin/sh".into(), "-c".into(), "echo 'a b'".into()]
+                ),
+                &Call::Cgroup(web(), "cpuset.cpus".to_string(), Some("0,1".to_string())),
+                &Call::Stop(web()),
+                &Call::Start(web()),
+            ]
+        );
+    }
 
-    let image = format!(
-        "{}:{}:{}",
-        container_build_file["image"]["distro"]
-            .to_string()
-            .trim_matches('\"'),
-        container_build_file["image"]["release"]
-            .to_string()
-            .trim_matches('\"'),
-        container_build_file["image"]["arch"]
-            .to_string()
-            .trim_matches('\"'),
-    );
+    #[test]
+    fn stages_hand_artifacts_on_and_are_removed() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(
+            tmp.path().join("LXCfile.toml"),
+            r#"
+name = "app"
+
+[[stage]]
+name = "builder"
+image = { distro = "alpine", release = "3.19", arch = "amd64" }
+run = [{ cmd = "make" }]
+
+[image]
+distro = "alpine"
+release = "3.19"
+arch = "amd64"
+
+[[copy]]
+from = "builder"
+host = "/out/app"
+container = "/usr/bin/app"
+"#,
+        )
+        .unwrap();
+
+        let mut rt = mock(&tmp.path().join("lxc"));
+        // Stand in for what `make` would have produced
+        let builder = rt.context().container_dir("app-stage-builder");
+        std::fs::create_dir_all(builder.join("rootfs/out")).unwrap();
+        std::fs::write(builder.join("rootfs/out/app"), "binary").unwrap();
+        std::fs::create_dir_all(rt.context().container_dir("app").join("rootfs/usr/bin")).unwrap();
+
+        build(&mut rt, build_args(tmp.path())).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(rt.container("app").rootfs.join("usr/bin/app")).unwrap(),
+            "binary"
+        );
+        assert!(!rt.containers.contains_key("app-stage-builder"));
+        assert!(!builder.join("rootfs").exists());
+
+        let builder = || "app-stage-builder".to_string();
+        let calls: Vec<&Call> = rt
+            .calls
+            .iter()
+            .filter(|call| !matches!(call, Call::Info(..)))
+            .collect();
+        assert_eq!(
+            calls[..4],
+            [
+                &Call::Create(builder()),
+                &Call::Start(builder()),
+                &Call::Attach(
+                    builder(),
+                    vec!["/bin/sh".into(), "-c".into(), "make".into()]
+                ),
+                &Call::Create("app".to_string()),
+            ]
+        );
+        assert_eq!(calls.last(), Some(&&Call::Destroy(builder())));
+    }
 
-    let config_option = if container_build_file["image"]
-        .as_table()
-        .unwrap()
-        .contains_key("config")
-    {
-        Some(container_build_file["image"]["config"].to_string())
-    } else {
-        Some(String::default())
-    };
+    #[test]
+    fn rebuilds_resume_at_the_first_changed_step() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(tmp.path().join("app.conf"), "port = 80\n").unwrap();
+        let lxcfile = |last: &str| {
+            format!(
+                r#"
+name = "web"
 
-    let dir = if container_build_file["image"]
-        .as_table()
-        .unwrap()
-        .contains_key("dir")
-    {
-        Some(container_build_file["image"]["dir"].to_string())
-    } else {
-        Some(String::default())
-    };
+[image]
+distro = "alpine"
+release = "3.19"
+arch = "amd64"
 
-    let network = if container_build_file["image"]
-        .as_table()
-        .unwrap()
-        .contains_key("network")
-    {
-        Some(container_build_file["image"]["network"].to_string())
-    } else {
-        Some(String::default())
-    };
+[[copy]]
+host = "app.conf"
+container = "/app.conf"
 
-    let container_name = container_build_file["name"]
-        .to_string()
-        .trim_matches('\"')
-        .to_string();
-
-    // Create container_build_file command
-    let create_command = create(CreateArgs {
-        name: container_name.clone(),
-        image: image.clone(),
-        config: config_option,
-        dir: dir.clone(),
-        network,
-    });
-    // Create container
-    run_command(create_command[0].clone());
-    if unsafe { STDOUT } {
-        println!("[+] Container created");
-    }
-
-    // WARNING: Currently not working, it doesn't break the deploy
-    // but the command isn't being executed on container boot up 😢
-    // 
-    // Create a shell script locally with the command
-    // and the copy this shell script to the containers
-    if container_build_file.contains_key("entrypoint") {
-        // Enables boot-time execution by placing it in /etc/profile.d dir
-        let path: String = if dir.clone().is_some_and(|dir| !dir.is_empty()) {
-            format!("{}/etc/profile.d/lxcapp.sh", dir.clone().unwrap())
-        } else {
-            format!(
-                "/var/lib/lxc/{}/rootfs/etc/profile.d/lxcapp.sh",
-                container_name
+[[run]]
+cmd = "apk add nginx"
+
+[[run]]
+cmd = "{}"
+"#,
+                last
             )
         };
+        let args = || BuildArgs {
+            no_cache: false,
+            ..build_args(tmp.path())
+        };
+        let attached = |rt: &MockRuntime| -> Vec<String> {
+            rt.calls
+                .iter()
+                .filter_map(|call| match call {
+                    Call::Attach(_, argv) => Some(argv[2].clone()),
+                    _ => None,
+                })
+                .collect()
+        };
 
-        // Create executable /etc/profile.d/lxcapp.sh
-        let mut container_config_file = OpenOptions::new()
-            .create_new(true)
-            .append(true)
-            .open(path)
+        let mut rt = mock(&tmp.path().join("lxc"));
+        std::fs::write(tmp.path().join("LXCfile.toml"), lxcfile("nginx -t")).unwrap();
+        build(&mut rt, args()).unwrap();
+        assert_eq!(attached(&rt), ["apk add nginx", "nginx -t"]);
+        assert_eq!(rt.container("web-cache").snapshots.len(), 4);
+        assert!(rt.container("web").rootfs.join("app.conf").exists());
+
+        // A changed step runs again along with every step after it
+        let rebuild = |rt: &mut MockRuntime, last: &str| {
+            delete(
+                rt,
+                DeleteArgs {
+                    name: "web".to_string(),
+                    force: true,
+                    ..Default::default()
+                },
+            )
             .unwrap();
+            rt.calls.clear();
+            std::fs::write(tmp.path().join("LXCfile.toml"), lxcfile(last)).unwrap();
+            build(rt, args()).unwrap();
+        };
+        rebuild(&mut rt, "nginx -T");
+        assert_eq!(attached(&rt), ["nginx -T"]);
+        assert!(rt.calls.contains(&Call::Restore(
+            "web-cache".to_string(),
+            "snap2".to_string(),
+            None
+        )));
+        assert!(!rt.calls.contains(&Call::Create("web-cache".to_string())));
+
+        std::fs::write(tmp.path().join("app.conf"), "port = 8080\n").unwrap();
+        rebuild(&mut rt, "nginx -T");
+        assert_eq!(attached(&rt), ["apk add nginx", "nginx -T"]);
+        assert_eq!(
+            std::fs::read_to_string(rt.container("web").rootfs.join("app.conf")).unwrap(),
+            "port = 8080\n"
+        );
+    }
 
-        // Writes commands from the entrypoint to the script /etc/profile.d/lxcapp.sh
-        _ = writeln!(
-            container_config_file,
-            "#!/bin/sh\n{}",
-            container_build_file["entrypoint"]
-                .to_string()
-                .trim_matches('\"')
-                .trim_matches('\'')
-                .to_string()
+    #[test]
+    fn entrypoint_becomes_a_service_of_the_init_system() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(
+            tmp.path().join("LXCfile.toml"),
+            r#"
+name = "web"
+entrypoint = "httpd -f -p 80 -h '/var/www'"
+restart = "always"
+
+[image]
+distro = "alpine"
+release = "3.19"
+arch = "amd64"
+"#,
+        )
+        .unwrap();
+
+        // What the alpine image boots with
+        let mut rt = mock(&tmp.path().join("lxc"));
+        let rootfs = rt.context().default_rootfs("web");
+        std::fs::create_dir_all(rootfs.join("sbin")).unwrap();
+        std::os::unix::fs::symlink("/bin/busybox", rootfs.join("sbin/init")).unwrap();
+        std::fs::write(rootfs.join("sbin/openrc-run"), "").unwrap();
+
+        build(&mut rt, build_args(tmp.path())).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(rootfs.join("usr/local/bin/lxcapp")).unwrap(),
+            "#!/bin/sh\nhttpd -f -p 80 -h '/var/www'\n"
+        );
+        let service = std::fs::read_to_string(rootfs.join("etc/init.d/lxcapp")).unwrap();
+        assert!(service.contains("command=\"/usr/local/bin/lxcapp\""));
+        assert!(service.contains("supervisor=supervise-daemon"));
+        assert_eq!(
+            std::fs::read_link(rootfs.join("etc/runlevels/default/lxcapp")).unwrap(),
+            Path::new("/etc/init.d/lxcapp")
         );
-        let _ = container_config_file.flush();
-
-        // Set execution permissions 
-        let mut perm = container_config_file.metadata().unwrap().permissions();
-        perm.set_mode(0o555);
-        let _ = container_config_file.set_permissions(perm);
-    }
-
-    run_command(format!("lxc-start {}", container_name));
-    if unsafe { STDOUT } {
-        println!("[+] Container started");
-    }
-
-    // Handle copies. In this case, the source is always
-    // the host and the destination is always the container
-    if container_build_file.contains_key("copy") {
-        if let Some(copies) = container_build_file["copy"].as_array() {
-            for copy_elem in copies {
-                let archive: bool = if copy_elem.as_table().unwrap().contains_key("archive") {
-                    copy_elem.as_table().unwrap()["archive"].as_bool().unwrap()
-                } else {
-                    false
-                };
-
-                let follow_link: bool = if copy_elem.as_table().unwrap().contains_key("follow_link")
-                {
-                    copy_elem.as_table().unwrap()["follow_link"]
-                        .as_bool()
-                        .unwrap()
-                } else {
-                    false
-                };
-
-                let x: &[_] = &['.', '\"'];
-                let copy_command = copy(CopyArgs {
-                    source: copy_elem["host"].to_string().trim_matches('\"').to_string(),
-                    destination: format!(
-                        "{}:{}",
-                        container_name,
-                        copy_elem["container"].to_string().trim_matches(x)
-                    ),
-                    archive,
-                    follow_link,
-                });
-                // Copy content
-                run_command(copy_command[0].clone());
-            }
-        }
-        if unsafe { STDOUT } {
-            println!("[+] Content copied to the container");
-        }
     }
 
-    // Handle shared volume
-    if container_build_file.contains_key("shared") {
-        if let Some(locations) = container_build_file["shared"].as_array() {
-            for location in locations {
-                let location_table = location.as_table().unwrap();
-                // Creates mount directory at the host, if it does not exist already
-                if !Path::new(&location_table["host"].to_string()).exists() {
-                    run_command(format!("mkdir -p {}", location_table["host"]));
-                }
+    #[test]
+    fn env_reaches_the_config_and_the_entrypoint() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(
+            tmp.path().join("LXCfile.toml"),
+            r#"
+name = "web"
+entrypoint = "httpd -f -p $PORT"
+
+[args]
+PORT = "80"
+
+[env]
+PORT = "${PORT}"
+GREETING = "hello world"
+
+[image]
+distro = "alpine"
+release = "3.19"
+arch = "amd64"
+"#,
+        )
+        .unwrap();
+
+        let mut rt = mock(&tmp.path().join("lxc"));
+        let rootfs = rt.context().default_rootfs("web");
+        std::fs::create_dir_all(rootfs.join("sbin")).unwrap();
+        std::fs::create_dir_all(rootfs.join("etc")).unwrap();
+        std::os::unix::fs::symlink("/bin/busybox", rootfs.join("sbin/init")).unwrap();
+        std::fs::write(rootfs.join("etc/inittab"), "::sysinit:/etc/init.d/rcS\n").unwrap();
+
+        build(
+            &mut rt,
+            BuildArgs {
+                build_arg: vec![("PORT".to_string(), "8080".to_string())],
+                ..build_args(tmp.path())
+            },
+        )
+        .unwrap();
+
+        let environment: Vec<&str> = rt
+            .container("web")
+            .config
+            .iter()
+            .filter(|(key, _)| key == "lxc.environment")
+            .map(|(_, value)| value.as_str())
+            .collect();
+        assert_eq!(environment, ["GREETING=hello world", "PORT=8080"]);
+        assert_eq!(
+            std::fs::read_to_string(rootfs.join("usr/local/bin/lxcapp")).unwrap(),
+            "#!/bin/sh\n\
+             export 'GREETING=hello world'\n\
+             export PORT=8080\n\
+             httpd -f -p $PORT\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(rootfs.join("etc/inittab")).unwrap(),
+            "::sysinit:/etc/init.d/rcS\n::once:/usr/local/bin/lxcapp\n"
+        );
+    }
 
-                // Edits container configuration file and enables volume mounting
-                let mut container_config_file = OpenOptions::new()
-                    .append(true)
-                    .open(format!("/var/lib/lxc/{}/config", container_name))
-                    .unwrap();
-
-                _ = writeln!(
-                    container_config_file,
-                    "lxc.mount.entry = {} {} none bind,create=dir 0 0",
-                    location_table["host"].to_string().trim_matches('\"'),
-                    location_table["container"].to_string().trim_matches('\"')
-                );
-            }
-        }
-        if unsafe { STDOUT } {
-            println!("[+] Shared volumes mounted");
-        }
+    #[test]
+    fn run_steps_take_their_user_and_workdir() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(
+            tmp.path().join("LXCfile.toml"),
+            r#"
+name = "web"
+user = "app"
+workdir = "/srv/app"
+
+[image]
+distro = "alpine"
+release = "3.19"
+arch = "amd64"
+
+[[run]]
+cmd = "make"
+
+[[run]]
+cmd = ["apk", "add", "make"]
+user = "root"
+group = "wheel"
+workdir = "/"
+"#,
+        )
+        .unwrap();
+
+        let mut rt = mock(&tmp.path().join("lxc"));
+        let etc = rt.context().default_rootfs("web").join("etc");
+        std::fs::create_dir_all(&etc).unwrap();
+        std::fs::write(
+            etc.join("passwd"),
+            "root:x:0:0:root:/root:/bin/sh
+app:x:1000:1000::/srv/app:/bin/sh
+",
+        )
+        .unwrap();
+        std::fs::write(
+            etc.join("group"),
+            "root:x:0:
+wheel:x:10:root
+",
+        )
+        .unwrap();
+
+        build(&mut rt, build_args(tmp.path())).unwrap();
+
+        let attached: Vec<&Vec<String>> = rt
+            .calls
+            .iter()
+            .filter_map(|call| match call {
+                Call::Attach(_, argv) => Some(argv),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            attached,
+            [
+                &vec![
+                    "/bin/sh",
+                    "-c",
+                    "cd \"$1\" && shift && exec \"$@\"",
+                    "sh",
+                    "/srv/app",
+                    "/bin/sh",
+                    "-c",
+                    "make"
+                ],
+                &vec![
+                    "/bin/sh",
+                    "-c",
+                    "cd \"$1\" && shift && exec \"$@\"",
+                    "sh",
+                    "/",
+                    "apk",
+                    "add",
+                    "make"
+                ],
+            ]
+        );
+        let id = |id: &str| Some(id.to_string());
+        assert_eq!(
+            rt.attached_as,
+            [(id("1000"), id("1000")), (id("0"), id("10"))]
+        );
     }
 
-    run_command(format!("lxc-stop {}", container_name));
-    run_command(format!("lxc-start {}", container_name));
+    #[test]
+    fn templates_are_rendered_with_args_and_env() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(
+            tmp.path().join("LXCfile.toml"),
+            r#"
+name = "web"
+
+[args]
+WORKERS = "2"
+
+[env]
+PORT = "8080"
+
+[image]
+distro = "alpine"
+release = "3.19"
+arch = "amd64"
+
+[[template]]
+source = "app.conf.in"
+container = "/etc/app/app.conf"
+mode = "0640"
+owner = "app"
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            tmp.path().join("app.conf.in"),
+            "host = ${HOSTNAME}\nport = ${PORT}\nworkers = ${WORKERS}\n",
+        )
+        .unwrap();
+
+        // Owned by whoever runs the test, as only root may give files away
+        let me = std::fs::metadata(tmp.path()).unwrap();
+        let mut rt = mock(&tmp.path().join("lxc"));
+        let etc = rt.context().default_rootfs("web").join("etc");
+        std::fs::create_dir_all(&etc).unwrap();
+        std::fs::write(
+            etc.join("passwd"),
+            format!("app:x:{}:{}::/:/bin/sh\n", me.uid(), me.gid()),
+        )
+        .unwrap();
+
+        let err = build(&mut rt, build_args(tmp.path())).unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("app.conf.in: undefined template variable `HOSTNAME`"));
+
+        let mut rt = mock(&tmp.path().join("lxc"));
+        std::fs::create_dir_all(&etc).unwrap();
+        std::fs::write(
+            etc.join("passwd"),
+            format!("app:x:{}:{}::/:/bin/sh\n", me.uid(), me.gid()),
+        )
+        .unwrap();
+        std::fs::write(
+            tmp.path().join("app.conf.in"),
+            "port = ${PORT}\nworkers = ${WORKERS}\n",
+        )
+        .unwrap();
+        build(&mut rt, build_args(tmp.path())).unwrap();
+
+        let rendered = etc.join("app/app.conf");
+        assert_eq!(
+            std::fs::read_to_string(&rendered).unwrap(),
+            "port = 8080\nworkers = 2\n"
+        );
+        let metadata = std::fs::metadata(&rendered).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o640);
+        assert_eq!((metadata.uid(), metadata.gid()), (me.uid(), me.gid()));
+    }
 
-    if unsafe { STDOUT } {
-        println!("[!] Running commands...");
+    #[test]
+    fn network_replaces_the_image_interfaces() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(
+            tmp.path().join("LXCfile.toml"),
+            r#"
+name = "web"
+
+[image]
+distro = "alpine"
+release = "3.19"
+arch = "amd64"
+
+[[network]]
+type = "veth"
+link = "lxcbr0"
+ipv4 = "10.0.3.10/24"
+ipv4_gateway = "auto"
+mtu = 1450
+
+[[network]]
+type = "macvlan"
+link = "eth1"
+hwaddr = "00:16:3e:xx:xx:xx"
+"#,
+        )
+        .unwrap();
+
+        let mut rt = mock(&tmp.path().join("lxc"));
+        build(&mut rt, build_args(tmp.path())).unwrap();
+
+        let network: Vec<String> = rt
+            .container("web")
+            .config
+            .iter()
+            .filter(|(key, _)| key.starts_with("lxc.net"))
+            .map(|(key, value)| format!("{} = {}", key, value))
+            .collect();
+        assert_eq!(
+            network,
+            [
+                "lxc.net = ",
+                "lxc.net.0.type = veth",
+                "lxc.net.0.flags = up",
+                "lxc.net.0.link = lxcbr0",
+                "lxc.net.0.ipv4.address = 10.0.3.10/24",
+                "lxc.net.0.ipv4.gateway = auto",
+                "lxc.net.0.mtu = 1450",
+                "lxc.net.1.type = macvlan",
+                "lxc.net.1.flags = up",
+                "lxc.net.1.link = eth1",
+                "lxc.net.1.hwaddr = 00:16:3e:xx:xx:xx",
+            ]
+        );
     }
 
-    // Handle run commands
-    if container_build_file.contains_key("run") {
-        if let Some(commands) = container_build_file["run"].as_array() {
-            for command in commands {
-                let cmd = command["cmd"].to_string().trim_matches('\"').to_string();
-                let run_content_command =
-                    format!("lxc-attach {} -- {}", container_name, cmd.clone());
-                if unsafe { STDOUT } {
-                    println!(" => {}", cmd.clone());
-                }
-                // Runs commands
-                run_command(run_content_command.clone());
+    #[test]
+    fn devices_are_allowed_and_mounted() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(
+            tmp.path().join("LXCfile.toml"),
+            r#"
+name = "web"
+
+[image]
+distro = "alpine"
+release = "3.19"
+arch = "amd64"
+
+[[device]]
+path = "/dev/null"
+container = "/dev/sink"
+access = "rw"
+"#,
+        )
+        .unwrap();
+
+        let mut rt = mock(&tmp.path().join("lxc"));
+        build(&mut rt, build_args(tmp.path())).unwrap();
+
+        // /dev/null is character device 1:3 on Linux
+        let config = &rt.container("web").config;
+        assert!(config.contains(&(devices_allow_key().to_string(), "c 1:3 rw".to_string())));
+        assert!(config.contains(&(
+            "lxc.mount.entry".to_string(),
+            "/dev/null dev/sink none bind,optional,create=file 0 0".to_string()
+        )));
+    }
+
+    #[test]
+    fn device_numbers_split_large_majors() {
+        // 4100:300 as glibc's makedev lays it out
+        let rdev = 0x0000_1000_0010_042c;
+        assert_eq!(device_numbers(rdev), (4100, 300));
+        assert_eq!(device_numbers(0x0103), (1, 3));
+    }
+
+    #[test]
+    fn copy_resolves_container_paths_against_rootfs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut rt = mock(tmp.path());
+        create_container(&mut rt, "src");
+        std::fs::write(rt.container("src").rootfs.join("file with spaces"), "data").unwrap();
+        let destination = tmp.path().join("out file");
+
+        copy(
+            &mut rt,
+            CopyArgs {
+                source: "src:/file with spaces".to_string(),
+                destination: destination.display().to_string(),
+                archive: false,
+                follow_link: false,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(std::fs::read_to_string(destination).unwrap(), "data");
+        assert!(rt.calls.contains(&Call::Info(
+            "src".to_string(),
+            Some("lxc.rootfs.path".to_string())
+        )));
+    }
+
+    #[test]
+    fn build_copies_keep_colons_in_host_paths() {
+        let tmp = tempfile::tempdir().unwrap();
+        let context = tmp.path().join("app:v2");
+        std::fs::create_dir(&context).unwrap();
+        std::fs::write(context.join("app.conf"), "port = 80\n").unwrap();
+        std::fs::write(
+            context.join("LXCfile.toml"),
+            r#"
+name = "web"
+
+[image]
+distro = "alpine"
+release = "3.19"
+arch = "amd64"
+
+[[copy]]
+host = "app.conf"
+container = "/app.conf"
+"#,
+        )
+        .unwrap();
+
+        let mut rt = mock(&tmp.path().join("lxc:data"));
+        build(&mut rt, build_args(&context)).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(rt.container("web").rootfs.join("app.conf")).unwrap(),
+            "port = 80\n"
+        );
+    }
+
+    #[test]
+    fn config_sets_and_reads_cgroup_items() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut rt = mock(tmp.path());
+        create_container(&mut rt, "db");
+        rt.containers.get_mut("db").unwrap().running = true;
+
+        config(
+            &mut rt,
+            ConfigArgs {
+                name: "db".to_string(),
+                state_object: Some(vec!["memory.max".to_string(), "256M".to_string()]),
+                config: None,
+                output: None,
+            },
+        )
+        .unwrap();
+        config(
+            &mut rt,
+            ConfigArgs {
+                name: "db".to_string(),
+                state_object: Some(vec!["memory.max".to_string()]),
+                config: None,
+                output: None,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(rt.container("db").cgroup["memory.max"], "256M");
+        assert_eq!(
+            rt.calls[1..],
+            [
+                Call::Cgroup("db".into(), "memory.max".into(), Some("256M".into())),
+                Call::Cgroup("db".into(), "memory.max".into(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn inspect_reads_state_groups_and_autostart() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut rt = mock(tmp.path());
+        create_container(&mut rt, "web");
+        rt.set_config("web", "lxc.group", "frontend").unwrap();
+        rt.set_config("web", "lxc.group", "prod").unwrap();
+        rt.set_config("web", "lxc.start.auto", "1").unwrap();
+        rt.containers.get_mut("web").unwrap().running = true;
+
+        assert_eq!(
+            rt.inspect("web").unwrap(),
+            ContainerInfo {
+                name: "web".to_string(),
+                state: "RUNNING".to_string(),
+                groups: vec!["frontend".to_string(), "prod".to_string()],
+                autostart: true,
+                ..Default::default()
             }
-        }
+        );
     }
 
-    // Handle limits
-    if container_build_file.contains_key("limits") {
-        let limits_table = container_build_file["limits"].as_table().unwrap();
+    #[test]
+    fn build_fails_with_the_exit_code_of_a_run_step() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(
+            tmp.path().join("LXCfile.toml"),
+            r#"
+name = "ci"
+
+[image]
+distro = "alpine"
+release = "3.19"
+arch = "amd64"
+
+[[run]]
+cmd = "false"
+
+[[run]]
+cmd = "true"
+"#,
+        )
+        .unwrap();
+
+        let mut rt = mock(&tmp.path().join("lxc"));
+        rt.attach_code = 3;
+        let err = build(&mut rt, build_args(tmp.path())).unwrap_err();
+
+        assert_eq!(err.exit_code(), 3);
+        let attached = rt
+            .calls
+            .iter()
+            .filter(|call| matches!(call, Call::Attach(..)))
+            .count();
+        assert_eq!(attached, 1);
+    }
 
-        // Applies limitation to system resources via `lxc-cgroups`
-        for limit in limits_table {
-            let config_command = config(ConfigArgs {
-                name: container_name.clone(),
-                state_object: Some(vec![
-                    limit.0.replace("_", ".").to_string(),
-                    limit.1.to_string().trim_matches('\"').to_string(),
-                ]),
-                config: Some(String::default()),
-            });
-            run_command(config_command[0].clone());
+    #[test]
+    fn failed_builds_are_rolled_back_unless_kept() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(
+            tmp.path().join("LXCfile.toml"),
+            r#"
+name = "app"
+
+[[stage]]
+name = "build"
+image = { distro = "alpine", release = "3.19", arch = "amd64" }
+
+[image]
+distro = "alpine"
+release = "3.19"
+arch = "amd64"
+
+[[copy]]
+from = "build"
+host = "/out/app"
+container = "/usr/bin/app"
+"#,
+        )
+        .unwrap();
+
+        // Nothing at /out/app, so the copy out of the stage fails
+        let mut rt = mock(&tmp.path().join("lxc"));
+        let build_log = tmp.path().join("build.log");
+        let err = build(
+            &mut rt,
+            BuildArgs {
+                build_log: Some(build_log.display().to_string()),
+                ..build_args(tmp.path())
+            },
+        )
+        .unwrap_err();
+        assert!(err.to_string().starts_with("build step copy[1] failed: "));
+        let steps: Vec<String> = std::fs::read_to_string(&build_log)
+            .unwrap()
+            .lines()
+            .filter_map(|line| line.strip_prefix("==> "))
+            .take_while(|line| !line.starts_with("total: "))
+            .filter_map(|line| line.split(" in ").next())
+            .map(str::to_string)
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                "stage[1].image: done",
+                "stage[1].start: done",
+                "image: done",
+                "env: done",
+                "start: done",
+                "copy[1]: failed",
+            ]
+        );
+        assert!(rt.containers.is_empty());
+        assert_eq!(
+            rt.calls.last(),
+            Some(&Call::Destroy("app-stage-build".to_string()))
+        );
+
+        let mut rt = mock(&tmp.path().join("lxc"));
+        build(
+            &mut rt,
+            BuildArgs {
+                keep_failed: true,
+                ..build_args(tmp.path())
+            },
+        )
+        .unwrap_err();
+        assert_eq!(
+            rt.containers.keys().collect::<Vec<_>>(),
+            ["app", "app-stage-build"]
+        );
+    }
+
+    #[test]
+    fn replicas_are_copies_with_their_own_volumes() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(
+            tmp.path().join("LXCfile.toml"),
+            r#"
+name = "web"
+replicas = 2
+
+[image]
+distro = "alpine"
+release = "3.19"
+arch = "amd64"
+
+[[shared]]
+host = "data/web-${REPLICA}"
+container = "var/lib/web"
+"#,
+        )
+        .unwrap();
+
+        let mut rt = mock(&tmp.path().join("lxc"));
+        build(
+            &mut rt,
+            BuildArgs {
+                replicas: Some(3),
+                ..build_args(tmp.path())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            rt.containers.keys().collect::<Vec<_>>(),
+            ["web-1", "web-2", "web-3"]
+        );
+        let clones: Vec<&Call> = rt
+            .calls
+            .iter()
+            .filter(|call| matches!(call, Call::Clone(..)))
+            .collect();
+        assert_eq!(
+            clones,
+            [
+                &Call::Clone("web-1".to_string(), "web-2".to_string()),
+                &Call::Clone("web-1".to_string(), "web-3".to_string()),
+            ]
+        );
+        for replica in 1..=3 {
+            let name = format!("web-{}", replica);
+            let host = tmp.path().join(format!("data/web-{}", replica));
+            assert!(host.is_dir());
+            assert!(rt.container(&name).running);
+            assert_eq!(
+                rt.container(&name).config,
+                [
+                    ("lxc.uts.name".to_string(), name.clone()),
+                    (
+                        "lxc.mount.entry".to_string(),
+                        format!("{} var/lib/web none bind,create=dir 0 0", host.display())
+                    )
+                ]
+            );
+            assert!(rt.context().container_dir(&name).join(BUILT_FROM).exists());
         }
+
+        // Inherited from by replica, not by the name they share
+        let child = tmp.path().join("child.toml");
+        let lxcpath = tmp.path().join("lxc");
+        std::fs::write(&child, "from = \"web-2\"\n").unwrap();
+        assert!(LxcFile::resolve(&child, &lxcpath).is_ok());
+        std::fs::write(&child, "from = \"web\"\n").unwrap();
+        let err = LxcFile::resolve(&child, &lxcpath).unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("container `web` was built as replicas, inherit from `web-1`"));
     }
 
-    run_command(format!("lxc-stop {}", container_name));
-    run_command(format!("lxc-start {}", container_name));
+    #[test]
+    fn existing_containers_are_replaced_or_updated() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(tmp.path().join("app.conf"), "port = 80\n").unwrap();
+        std::fs::write(
+            tmp.path().join("LXCfile.toml"),
+            r#"
+name = "web"
+
+[image]
+distro = "alpine"
+release = "3.19"
+arch = "amd64"
+
+[[copy]]
+host = "app.conf"
+container = "/app.conf"
+
+[[shared]]
+host = "data"
+container = "var/lib/web"
+
+[[run]]
+cmd = "make"
+"#,
+        )
+        .unwrap();
+        let args = |replace, update| BuildArgs {
+            name: Some("web-dev".to_string()),
+            replace,
+            update,
+            ..build_args(tmp.path())
+        };
 
-    return vec!["echo [+] Container created".to_string()];
-}
+        let mut rt = mock(&tmp.path().join("lxc"));
+        build(&mut rt, args(false, false)).unwrap();
+        assert_eq!(rt.containers.keys().collect::<Vec<_>>(), ["web-dev"]);
+
+        // Left alone, as this build did not create it
+        build(&mut rt, args(false, false)).unwrap_err();
+        assert!(rt.containers.contains_key("web-dev"));
+
+        rt.calls.clear();
+        build(&mut rt, args(true, false)).unwrap();
+        assert_eq!(rt.calls[1], Call::Destroy("web-dev".to_string()));
+        assert!(rt.container("web-dev").running);
+
+        rt.calls.clear();
+        std::fs::write(tmp.path().join("app.conf"), "port = 8080\n").unwrap();
+        build(&mut rt, args(false, true)).unwrap();
+        let rootfs = rt.container("web-dev").rootfs.clone();
+        assert_eq!(
+            std::fs::read_to_string(rootfs.join("app.conf")).unwrap(),
+            "port = 8080\n"
+        );
+        assert_eq!(rt.container("web-dev").config.len(), 1);
+        assert!(rt.container("web-dev").running);
+        assert!(!rt.calls.iter().any(|call| matches!(call, Call::Create(..))));
+        assert!(rt.calls.contains(&Call::Attach(
+            "web-dev".to_string(),
+            vec!["/bin/sh".into(), "-c".into(), "make".into()]
+        )));
+    }
 
-fn run_command(command: String) {
-    let p_out = if unsafe { STDOUT } {
-        Stdio::inherit()
-    } else {
-        Stdio::null()
-    };
-    let p_err = if unsafe { STDERR } {
-        Stdio::inherit()
-    } else {
-        Stdio::null()
-    };
+    #[test]
+    fn dry_run_build_touches_nothing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let shared = tmp.path().join("shared");
+        std::fs::write(
+            tmp.path().join("LXCfile.toml"),
+            format!(
+                r#"
+name = "web"
+entrypoint = "httpd -f"
+
+[image]
+distro = "alpine"
+release = "3.19"
+arch = "amd64"
+
+[[copy]]
+host = "LXCfile.toml"
+container = "/LXCfile.toml"
+
+[[shared]]
+host = "{}"
+container = "mnt/shared"
+
+[[run]]
+cmd = "apk add busybox-extras"
+"#,
+                shared.display()
+            ),
+        )
+        .unwrap();
+
+        // Nothing is spawned either, so this passes without lxc installed
+        let lxcpath = tmp.path().join("lxc");
+        let mut rt = LxcTools::new(Context {
+            lxcpath: Some(lxcpath.display().to_string()),
+            dry_run: true,
+            ..Default::default()
+        });
+        build(&mut rt, build_args(tmp.path())).unwrap();
+
+        assert!(!lxcpath.exists());
+        assert!(!shared.exists());
+    }
 
-    let mut command_and_args: Vec<&str> = command.split_whitespace().collect();
-    match Command::new(command_and_args[0])
-        .args(command_and_args.split_off(1))
-        .stdout(p_out)
-        .stderr(p_err)
-        .spawn()
-    {
-        Ok(mut shell) => {
-            let _ = shell.wait();
-        }
-        Err(e) => {
-            println!("{:?}", e);
-        }
+    #[test]
+    fn global_options_reach_the_runtime() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut rt = MockRuntime::new(Context {
+            lxcpath: Some(tmp.path().display().to_string()),
+            logfile: Some("/tmp/cmt.log".to_string()),
+            logpriority: Some("DEBUG".to_string()),
+            ..Default::default()
+        });
+        create_container(&mut rt, "web");
+        assert!(tmp.path().join("web/rootfs").is_dir());
+
+        let name = || "web".to_string();
+        let rcfile = || Some("/etc/lxc/web.rc".to_string());
+        start(
+            &mut rt,
+            StartArgs {
+                name: name(),
+                rcfile: rcfile(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        stop(
+            &mut rt,
+            StopArgs {
+                name: name(),
+                rcfile: rcfile(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        delete(
+            &mut rt,
+            DeleteArgs {
+                name: name(),
+                rcfile: rcfile(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // What every lxc tool is started with
+        assert_eq!(
+            rt.context().lxc_args(),
+            [
+                format!("--lxcpath={}", tmp.path().display()),
+                "--logfile=/tmp/cmt.log".to_string(),
+                "--logpriority=DEBUG".to_string(),
+            ]
+        );
+        assert_eq!(rt.rcfiles, [rcfile(), rcfile(), rcfile()]);
+    }
+
+    fn mock(lxcpath: &Path) -> MockRuntime {
+        MockRuntime::new(Context {
+            lxcpath: Some(lxcpath.display().to_string()),
+            ..Default::default()
+        })
+    }
+
+    fn create_container(rt: &mut MockRuntime, name: &str) {
+        create(
+            rt,
+            CreateArgs {
+                name: name.to_string(),
+                image: "alpine:3.19:amd64".to_string(),
+                config: None,
+                dir: None,
+                network: None,
+            },
+        )
+        .unwrap();
     }
 }
@@ -0,0 +1,186 @@
+// Copyright 2024 David Araújo
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+use std::io::IsTerminal;
+use std::sync::{Arc, Mutex};
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::command::Cmd;
+use crate::error::Error;
+
+/// How much cmt reports about what it is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Verbosity {
+    /// Errors only. The output of the lxc tools is discarded as well.
+    Quiet,
+    /// Progress of each command.
+    #[default]
+    Normal,
+    /// Also every external command before it runs.
+    Verbose,
+    /// Also cmt's own decisions.
+    Debug,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorChoice {
+    /// Color when writing to a terminal and NO_COLOR is unset
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Something worth telling the user about.
+pub enum Event<'a> {
+    /// A stage of a command is starting: `[!] Running commands...`
+    Stage(&'a str),
+    /// A stage or a whole command finished: `[+] Container created`
+    Done(&'a str),
+    /// One item of the current stage, such as a run step or a limit
+    Step(&'a dyn fmt::Display),
+    /// An external command about to run
+    Command(&'a Cmd),
+    Debug(String),
+    /// Something wrong, shown on stderr whatever the verbosity:
+    /// `[-] ./LXCfile.toml: replicas: must be at least 1`
+    Problem(&'a dyn fmt::Display),
+}
+
+impl Event<'_> {
+    fn verbosity(&self) -> Verbosity {
+        match self {
+            Event::Stage(_) | Event::Done(_) | Event::Step(_) => Verbosity::Normal,
+            Event::Command(_) => Verbosity::Verbose,
+            Event::Debug(_) => Verbosity::Debug,
+            Event::Problem(_) => Verbosity::Quiet,
+        }
+    }
+}
+
+// ANSI colors used for the event prefixes
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+const RED: &str = "31";
+const DIM: &str = "2";
+
+/// Where progress and errors go, and how much of them.
+#[derive(Debug, Clone, Default)]
+pub struct Output {
+    pub verbosity: Verbosity,
+    color: bool,
+    /// What external commands printed, when kept. Shared by every clone.
+    transcript: Arc<Mutex<Option<Vec<u8>>>>,
+}
+
+impl Output {
+    pub fn new(verbosity: Verbosity, color: ColorChoice) -> Output {
+        let color = match color {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                std::env::var_os("NO_COLOR").is_none() && std::io::stdout().is_terminal()
+            }
+        };
+        Output {
+            verbosity,
+            color,
+            ..Default::default()
+        }
+    }
+
+    /// Whether anything at `verbosity` is reported.
+    pub fn shows(&self, verbosity: Verbosity) -> bool {
+        self.verbosity >= verbosity
+    }
+
+    pub fn emit(&self, event: Event) {
+        if !self.shows(event.verbosity()) {
+            return;
+        }
+
+        match event {
+            Event::Stage(message) => println!("{} {}", self.paint(YELLOW, "[!]"), message),
+            Event::Done(message) => println!("{} {}", self.paint(GREEN, "[+]"), message),
+            Event::Step(step) => println!(" => {}", step),
+            Event::Command(cmd) => println!("{}", self.paint(DIM, &format!(" $ {}", cmd))),
+            Event::Debug(message) => println!("{}", self.paint(DIM, &format!("[*] {}", message))),
+            Event::Problem(problem) => eprintln!("{} {}", self.paint(RED, "[-]"), problem),
+        }
+    }
+
+    /// Keeps what external commands print from now on, on top of showing
+    /// it, until taken with `take_transcript`.
+    pub fn keep_transcript(&self) {
+        *self.transcript.lock().unwrap() = Some(Vec::new());
+    }
+
+    pub fn keeps_transcript(&self) -> bool {
+        self.transcript.lock().unwrap().is_some()
+    }
+
+    /// What was kept since the last call, nothing unless kept at all.
+    pub fn take_transcript(&self) -> Vec<u8> {
+        self.transcript
+            .lock()
+            .unwrap()
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    pub fn transcribe(&self, bytes: &[u8]) {
+        if let Some(transcript) = self.transcript.lock().unwrap().as_mut() {
+            transcript.extend_from_slice(bytes);
+        }
+    }
+
+    /// Reports `e` on stderr, whatever the verbosity.
+    pub fn error(&self, e: &Error) {
+        eprintln!("{} Error: {}", self.paint(RED, "[-]"), e);
+    }
+
+    fn paint(&self, color: &str, text: &str) -> String {
+        if self.color {
+            format!("\x1b[{}m{}\x1b[0m", color, text)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verbosity_and_color_follow_the_options() {
+        let quiet = Output::new(Verbosity::Quiet, ColorChoice::Never);
+        assert!(!quiet.shows(Event::Stage("").verbosity()));
+        assert!(quiet.shows(Event::Problem(&"").verbosity()));
+        let normal = Output::new(Verbosity::Normal, ColorChoice::Never);
+        assert!(normal.shows(Event::Done("").verbosity()));
+        assert!(!normal.shows(Event::Command(&Cmd::new("true")).verbosity()));
+        let debug = Output::new(Verbosity::Debug, ColorChoice::Never);
+        assert!(debug.shows(Event::Debug(String::new()).verbosity()));
+
+        assert_eq!(normal.paint(GREEN, "[+]"), "[+]");
+        let colored = Output::new(Verbosity::Normal, ColorChoice::Always);
+        assert_eq!(colored.paint(GREEN, "[+]"), "\x1b[32m[+]\x1b[0m");
+    }
+}
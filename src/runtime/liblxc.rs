@@ -0,0 +1,666 @@
+// Copyright 2024 David Araújo
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::ptr;
+
+use super::{LxcTools, Runtime};
+use crate::context::Context;
+use crate::error::{Error, Result};
+use crate::{CreateArgs, DeleteArgs, ExecuteArgs, ListArgs, StartArgs, StopArgs};
+
+// Mirrors the parts of <lxc/lxccontainer.h> and <lxc/attach_options.h>
+// used below. Fields must stay in header order up to the last one read.
+#[allow(non_camel_case_types, dead_code)]
+mod ffi {
+    use libc::{c_char, c_int, c_long, c_void, gid_t, pid_t, uid_t};
+
+    type Fn0<R> = Option<unsafe extern "C" fn(*mut lxc_container) -> R>;
+
+    #[repr(C)]
+    pub struct lxc_container {
+        name: *mut c_char,
+        configfile: *mut c_char,
+        pidfile: *mut c_char,
+        slock: *mut c_void,
+        privlock: *mut c_void,
+        numthreads: c_int,
+        lxc_conf: *mut c_void,
+        pub error_string: *mut c_char,
+        pub error_num: c_int,
+        pub daemonize: bool,
+        pub config_path: *mut c_char,
+
+        pub is_defined: Fn0<bool>,
+        pub state: Fn0<*const c_char>,
+        pub is_running: Fn0<bool>,
+        pub freeze: Fn0<bool>,
+        pub unfreeze: Fn0<bool>,
+        pub init_pid: Fn0<pid_t>,
+        pub load_config: Option<unsafe extern "C" fn(*mut lxc_container, *const c_char) -> bool>,
+        pub start:
+            Option<unsafe extern "C" fn(*mut lxc_container, c_int, *const *const c_char) -> bool>,
+        pub startl: *mut c_void,
+        pub stop: Fn0<bool>,
+        pub want_daemonize: Option<unsafe extern "C" fn(*mut lxc_container, bool) -> bool>,
+        pub want_close_all_fds: Option<unsafe extern "C" fn(*mut lxc_container, bool) -> bool>,
+        pub config_file_name: Fn0<*mut c_char>,
+        pub wait: Option<unsafe extern "C" fn(*mut lxc_container, *const c_char, c_int) -> bool>,
+        pub set_config_item:
+            Option<unsafe extern "C" fn(*mut lxc_container, *const c_char, *const c_char) -> bool>,
+        pub destroy: Fn0<bool>,
+        pub save_config: Option<unsafe extern "C" fn(*mut lxc_container, *const c_char) -> bool>,
+        pub create: Option<
+            unsafe extern "C" fn(
+                *mut lxc_container,
+                *const c_char,
+                *const c_char,
+                *mut c_void,
+                c_int,
+                *const *const c_char,
+            ) -> bool,
+        >,
+        pub createl: *mut c_void,
+        pub rename: Option<unsafe extern "C" fn(*mut lxc_container, *const c_char) -> bool>,
+        pub reboot: Fn0<bool>,
+        pub shutdown: Option<unsafe extern "C" fn(*mut lxc_container, c_int) -> bool>,
+        pub clear_config: Option<unsafe extern "C" fn(*mut lxc_container)>,
+        pub clear_config_item:
+            Option<unsafe extern "C" fn(*mut lxc_container, *const c_char) -> bool>,
+        pub get_config_item: Option<
+            unsafe extern "C" fn(*mut lxc_container, *const c_char, *mut c_char, c_int) -> c_int,
+        >,
+        pub get_running_config_item:
+            Option<unsafe extern "C" fn(*mut lxc_container, *const c_char) -> *mut c_char>,
+        pub get_keys: Option<
+            unsafe extern "C" fn(*mut lxc_container, *const c_char, *mut c_char, c_int) -> c_int,
+        >,
+        pub get_interfaces: Fn0<*mut *mut c_char>,
+        pub get_ips: Option<
+            unsafe extern "C" fn(
+                *mut lxc_container,
+                *const c_char,
+                *const c_char,
+                c_int,
+            ) -> *mut *mut c_char,
+        >,
+        pub get_cgroup_item: Option<
+            unsafe extern "C" fn(*mut lxc_container, *const c_char, *mut c_char, c_int) -> c_int,
+        >,
+        pub set_cgroup_item:
+            Option<unsafe extern "C" fn(*mut lxc_container, *const c_char, *const c_char) -> bool>,
+        pub get_config_path: Fn0<*const c_char>,
+        pub set_config_path:
+            Option<unsafe extern "C" fn(*mut lxc_container, *const c_char) -> bool>,
+        pub clone: *mut c_void,
+        pub console_getfd: *mut c_void,
+        pub console: *mut c_void,
+        pub attach: *mut c_void,
+        pub attach_run_wait: Option<
+            unsafe extern "C" fn(
+                *mut lxc_container,
+                *mut lxc_attach_options_t,
+                *const c_char,
+                *const *const c_char,
+            ) -> c_int,
+        >,
+    }
+
+    #[repr(C)]
+    pub struct lxc_groups_t {
+        pub size: c_int,
+        pub list: *mut gid_t,
+    }
+
+    #[repr(C)]
+    pub struct lxc_attach_options_t {
+        pub attach_flags: c_int,
+        pub namespaces: c_int,
+        pub personality: c_long,
+        pub initial_cwd: *mut c_char,
+        pub uid: uid_t,
+        pub gid: gid_t,
+        pub env_policy: c_int,
+        pub extra_env_vars: *mut *mut c_char,
+        pub extra_keep_env: *mut *mut c_char,
+        pub stdin_fd: c_int,
+        pub stdout_fd: c_int,
+        pub stderr_fd: c_int,
+        pub log_fd: c_int,
+        pub lsm_label: *mut c_char,
+        pub groups: lxc_groups_t,
+    }
+
+    #[repr(C)]
+    pub struct lxc_log {
+        pub name: *const c_char,
+        pub lxcpath: *const c_char,
+        pub file: *const c_char,
+        pub level: *const c_char,
+        pub prefix: *const c_char,
+        pub quiet: bool,
+    }
+
+    pub const LXC_ATTACH_DEFAULT: c_int = 0x0000FFFF;
+    pub const LXC_ATTACH_KEEP_ENV: c_int = 0;
+    pub const LXC_ATTACH_CLEAR_ENV: c_int = 1;
+
+    impl Default for lxc_attach_options_t {
+        // LXC_ATTACH_OPTIONS_DEFAULT
+        fn default() -> Self {
+            lxc_attach_options_t {
+                attach_flags: LXC_ATTACH_DEFAULT,
+                namespaces: -1,
+                personality: -1,
+                initial_cwd: std::ptr::null_mut(),
+                uid: uid_t::MAX,
+                gid: gid_t::MAX,
+                env_policy: LXC_ATTACH_KEEP_ENV,
+                extra_env_vars: std::ptr::null_mut(),
+                extra_keep_env: std::ptr::null_mut(),
+                stdin_fd: 0,
+                stdout_fd: 1,
+                stderr_fd: 2,
+                log_fd: -libc::EBADF,
+                lsm_label: std::ptr::null_mut(),
+                groups: lxc_groups_t {
+                    size: 0,
+                    list: std::ptr::null_mut(),
+                },
+            }
+        }
+    }
+
+    #[link(name = "lxc")]
+    extern "C" {
+        pub fn lxc_container_new(
+            name: *const c_char,
+            configpath: *const c_char,
+        ) -> *mut lxc_container;
+        pub fn lxc_container_put(c: *mut lxc_container) -> c_int;
+        pub fn lxc_log_init(log: *mut lxc_log) -> c_int;
+    }
+}
+
+/// Owned reference to a `struct lxc_container`.
+struct Container(*mut ffi::lxc_container);
+
+impl Container {
+    fn new(name: &str, lxcpath: Option<&CStr>) -> Option<Container> {
+        let name = CString::new(name).ok()?;
+        let lxcpath = lxcpath.map_or(ptr::null(), CStr::as_ptr);
+        let c = unsafe { ffi::lxc_container_new(name.as_ptr(), lxcpath) };
+        (!c.is_null()).then_some(Container(c))
+    }
+
+    fn api(&self) -> &ffi::lxc_container {
+        unsafe { &*self.0 }
+    }
+
+    fn state(&self) -> String {
+        let state = unsafe { (self.api().state.unwrap())(self.0) };
+        if state.is_null() {
+            return String::from("UNKNOWN");
+        }
+        unsafe { CStr::from_ptr(state) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn is_running(&self) -> bool {
+        unsafe { (self.api().is_running.unwrap())(self.0) }
+    }
+
+    fn init_pid(&self) -> i32 {
+        unsafe { (self.api().init_pid.unwrap())(self.0) }
+    }
+
+    fn ips(&self) -> Vec<String> {
+        let ips = unsafe { (self.api().get_ips.unwrap())(self.0, ptr::null(), ptr::null(), 0) };
+        take_string_array(ips)
+    }
+
+    // Both getters follow the snprintf convention: call once to size the
+    // buffer, then again to fill it
+    fn read_item(
+        &self,
+        getter: unsafe extern "C" fn(
+            *mut ffi::lxc_container,
+            *const c_char,
+            *mut c_char,
+            c_int,
+        ) -> c_int,
+        key: &str,
+    ) -> Option<String> {
+        let key = CString::new(key).ok()?;
+        let len = unsafe { getter(self.0, key.as_ptr(), ptr::null_mut(), 0) };
+        if len < 0 {
+            return None;
+        }
+        let mut buffer = vec![0u8; len as usize + 1];
+        unsafe {
+            getter(
+                self.0,
+                key.as_ptr(),
+                buffer.as_mut_ptr().cast(),
+                buffer.len() as c_int,
+            )
+        };
+        let value = CStr::from_bytes_until_nul(&buffer).ok()?;
+        Some(value.to_string_lossy().into_owned())
+    }
+
+    fn config_item(&self, key: &str) -> Option<String> {
+        self.read_item(self.api().get_config_item.unwrap(), key)
+    }
+
+    fn cgroup_item(&self, key: &str) -> Option<String> {
+        self.read_item(self.api().get_cgroup_item.unwrap(), key)
+    }
+}
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        unsafe { ffi::lxc_container_put(self.0) };
+    }
+}
+
+// Copies a NULL-terminated `char **` returned by liblxc and frees it
+fn take_string_array(array: *mut *mut c_char) -> Vec<String> {
+    let mut strings = Vec::new();
+    if array.is_null() {
+        return strings;
+    }
+    unsafe {
+        let mut i = 0;
+        while !(*array.add(i)).is_null() {
+            let item = *array.add(i);
+            strings.push(CStr::from_ptr(item).to_string_lossy().into_owned());
+            libc::free(item.cast());
+            i += 1;
+        }
+        libc::free(array.cast());
+    }
+    strings
+}
+
+// NULL-terminated argv backed by `strings`
+fn c_argv(strings: &[CString]) -> Vec<*const c_char> {
+    strings
+        .iter()
+        .map(|s| s.as_ptr())
+        .chain(std::iter::once(ptr::null()))
+        .collect()
+}
+
+fn c_strings<I, S>(items: I) -> Option<Vec<CString>>
+where
+    I: IntoIterator<Item = S>,
+    S: Into<Vec<u8>>,
+{
+    items.into_iter().map(|s| CString::new(s).ok()).collect()
+}
+
+/// Runtime talking to liblxc directly through the `lxc_container` API.
+/// Options the API has no equivalent for are handed to `LxcTools`.
+pub struct LibLxc {
+    lxcpath: Option<CString>,
+    fallback: LxcTools,
+}
+
+impl LibLxc {
+    pub fn new(ctx: Context) -> LibLxc {
+        let lxcpath = ctx
+            .lxcpath
+            .as_deref()
+            .and_then(|path| CString::new(path).ok());
+
+        // Same logging the lxc tools set up from --logfile/--logpriority
+        if ctx.logfile.is_some() || ctx.logpriority.is_some() {
+            let file = ctx
+                .logfile
+                .as_deref()
+                .and_then(|file| CString::new(file).ok());
+            let level = ctx
+                .logpriority
+                .as_deref()
+                .and_then(|level| CString::new(level).ok());
+            let mut log = ffi::lxc_log {
+                name: c"cmt".as_ptr(),
+                lxcpath: lxcpath.as_deref().map_or(ptr::null(), CStr::as_ptr),
+                file: file.as_deref().map_or(ptr::null(), CStr::as_ptr),
+                level: level.as_deref().map_or(ptr::null(), CStr::as_ptr),
+                prefix: c"cmt".as_ptr(),
+                quiet: false,
+            };
+            unsafe { ffi::lxc_log_init(&mut log) };
+        }
+
+        LibLxc {
+            lxcpath,
+            fallback: LxcTools::new(ctx),
+        }
+    }
+
+    // No container means the lxc tools take over. That includes dry runs,
+    // where they print each command instead of running it.
+    fn container(&self, name: &str) -> Option<Container> {
+        if self.fallback.context().dry_run {
+            return None;
+        }
+        Container::new(name, self.lxcpath.as_deref())
+    }
+}
+
+impl Runtime for LibLxc {
+    fn context(&self) -> &Context {
+        self.fallback.context()
+    }
+
+    fn create(&mut self, args: &CreateArgs) -> Result<()> {
+        let image: Vec<&str> = args.image.split(':').collect();
+        let plain = args.config.as_deref().unwrap_or_default().is_empty()
+            && args.dir.as_deref().unwrap_or_default().is_empty()
+            && args.network.as_deref().unwrap_or_default().is_empty()
+            && image.len() == 3;
+        let container = self.container(&args.name).filter(|_| plain);
+        let Some(container) = container else {
+            return self.fallback.create(args);
+        };
+
+        let Some(template_args) = c_strings([
+            "--dist",
+            image[0],
+            "--release",
+            image[1],
+            "--arch",
+            image[2],
+        ]) else {
+            return self.fallback.create(args);
+        };
+        let argv = c_argv(&template_args);
+        let template = c"download";
+
+        let created = unsafe {
+            (container.api().create.unwrap())(
+                container.0,
+                template.as_ptr(),
+                ptr::null(),
+                ptr::null_mut(),
+                0,
+                argv.as_ptr(),
+            )
+        };
+        if !created {
+            return Err(Error::Runtime(format!(
+                "failed to create container {}",
+                args.name
+            )));
+        }
+        Ok(())
+    }
+
+    fn destroy(&mut self, args: &DeleteArgs) -> Result<()> {
+        let container = self
+            .container(&args.name)
+            .filter(|_| !args.snapshots && args.rcfile.is_none());
+        let Some(container) = container else {
+            return self.fallback.destroy(args);
+        };
+
+        if container.is_running() {
+            if !args.force {
+                return Err(Error::Runtime(format!("{} is running", args.name)));
+            }
+            unsafe { (container.api().stop.unwrap())(container.0) };
+        }
+
+        if !unsafe { (container.api().destroy.unwrap())(container.0) } {
+            return Err(Error::Runtime(format!(
+                "failed to destroy container {}",
+                args.name
+            )));
+        }
+        Ok(())
+    }
+
+    fn start(&mut self, args: &StartArgs) -> Result<()> {
+        let plain = !args.foreground
+            && args.pidfile.is_none()
+            && args.rcfile.is_none()
+            && args.console.is_none()
+            && args.console_log.is_none()
+            && args.define.is_none()
+            && args.share_net.is_none()
+            && args.share_ipc.is_none()
+            && args.share_uts.is_none()
+            && args.share_pid.is_none();
+        let container = self.container(&args.name).filter(|_| plain);
+        let Some(container) = container else {
+            return self.fallback.start(args);
+        };
+
+        let started = unsafe {
+            (container.api().want_daemonize.unwrap())(container.0, true);
+            (container.api().want_close_all_fds.unwrap())(container.0, true);
+            (container.api().start.unwrap())(container.0, 0, ptr::null())
+        };
+        if !started {
+            return Err(Error::Runtime(format!(
+                "failed to start container {}",
+                args.name
+            )));
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self, args: &StopArgs) -> Result<()> {
+        let plain = !args.nowait && !args.nolock && args.rcfile.is_none();
+        let container = self.container(&args.name).filter(|_| plain);
+        let Some(container) = container else {
+            return self.fallback.stop(args);
+        };
+
+        let api = container.api();
+        let stopped = unsafe {
+            if args.reboot {
+                (api.reboot.unwrap())(container.0)
+            } else if args.kill {
+                (api.stop.unwrap())(container.0)
+            } else {
+                // Same defaults as lxc-stop: clean shutdown, then kill
+                let timeout = args.timeout.unwrap_or(60).min(c_int::MAX as u64) as c_int;
+                (api.shutdown.unwrap())(container.0, timeout)
+                    || (!args.nokill && (api.stop.unwrap())(container.0))
+            }
+        };
+        if !stopped {
+            return Err(Error::Runtime(format!(
+                "failed to stop container {}",
+                args.name
+            )));
+        }
+        Ok(())
+    }
+
+    fn attach(&mut self, args: &ExecuteArgs) -> Result<ExitStatus> {
+        let uid = args.uid.as_deref().map(str::parse::<u32>).transpose();
+        let gid = args.gid.as_deref().map(str::parse::<u32>).transpose();
+        // Output written by the attached process itself cannot be kept
+        let plain = !args.command.is_empty()
+            && !self.context().output.keeps_transcript()
+            && args.elevated_privileges.is_none()
+            && args.arch.is_none()
+            && args.namespaces.is_none()
+            && args.remount_sys_proc.is_none()
+            && args.pty_log.is_none()
+            && args.rcfile.is_none()
+            && args.context.is_none();
+        let (Ok(uid), Ok(gid), true) = (uid, gid, plain) else {
+            return self.fallback.attach(args);
+        };
+        let (Some(container), Some(argv), Some(set_var), Some(keep_var)) = (
+            self.container(&args.name),
+            c_strings(args.command.clone()),
+            c_strings(args.set_var.clone()),
+            c_strings(args.keep_var.clone()),
+        ) else {
+            return self.fallback.attach(args);
+        };
+
+        let mut options = ffi::lxc_attach_options_t::default();
+        if let Some(uid) = uid {
+            options.uid = uid;
+        }
+        if let Some(gid) = gid {
+            options.gid = gid;
+        }
+        if args.clear_env {
+            options.env_policy = ffi::LXC_ATTACH_CLEAR_ENV;
+        }
+        // Both lists are NULL terminated and only read by liblxc
+        let (c_set_var, c_keep_var) = (c_argv(&set_var), c_argv(&keep_var));
+        if !set_var.is_empty() {
+            options.extra_env_vars = c_set_var.as_ptr() as *mut *mut c_char;
+        }
+        if !keep_var.is_empty() {
+            options.extra_keep_env = c_keep_var.as_ptr() as *mut *mut c_char;
+        }
+
+        let c_args = c_argv(&argv);
+        let status = unsafe {
+            (container.api().attach_run_wait.unwrap())(
+                container.0,
+                &mut options,
+                argv[0].as_ptr(),
+                c_args.as_ptr(),
+            )
+        };
+        if status < 0 {
+            return Err(Error::Runtime(format!(
+                "failed to attach to container {}",
+                args.name
+            )));
+        }
+        Ok(ExitStatus::from_raw(status))
+    }
+
+    fn info(&mut self, name: &str, key: Option<&str>) -> Result<String> {
+        let Some(container) = self.container(name) else {
+            return self.fallback.info(name, key);
+        };
+
+        if let Some(key) = key {
+            return Ok(container.config_item(key).unwrap_or_default());
+        }
+
+        let mut info = format!(
+            "Name:           {}\nState:          {}\n",
+            name,
+            container.state()
+        );
+        if container.is_running() {
+            info.push_str(&format!("PID:            {}\n", container.init_pid()));
+            for ip in container.ips() {
+                info.push_str(&format!("IP:             {}\n", ip));
+            }
+            // cgroup v2 first, then v1
+            let memory = container
+                .cgroup_item("memory.current")
+                .or_else(|| container.cgroup_item("memory.usage_in_bytes"));
+            if let Some(memory) = memory {
+                info.push_str(&format!("Memory use:     {}\n", memory.trim()));
+            }
+        }
+        Ok(info)
+    }
+
+    fn cgroup(&mut self, name: &str, key: &str, value: Option<&str>) -> Result<String> {
+        let Some(container) = self.container(name) else {
+            return self.fallback.cgroup(name, key, value);
+        };
+
+        match value {
+            Some(value) => {
+                let (Ok(c_key), Ok(c_value)) = (CString::new(key), CString::new(value)) else {
+                    return self.fallback.cgroup(name, key, Some(value));
+                };
+                let set = unsafe {
+                    (container.api().set_cgroup_item.unwrap())(
+                        container.0,
+                        c_key.as_ptr(),
+                        c_value.as_ptr(),
+                    )
+                };
+                if !set {
+                    return Err(Error::Runtime(format!(
+                        "failed to set {} for {}",
+                        key, name
+                    )));
+                }
+                Ok(String::new())
+            }
+            None => container
+                .cgroup_item(key)
+                .ok_or_else(|| Error::Runtime(format!("failed to read {} for {}", key, name))),
+        }
+    }
+
+    fn list(&mut self, args: &ListArgs) -> Result<String> {
+        self.fallback.list(args)
+    }
+
+    fn set_config(&mut self, name: &str, key: &str, value: &str) -> Result<()> {
+        let (Some(container), Ok(c_key), Ok(c_value)) =
+            (self.container(name), CString::new(key), CString::new(value))
+        else {
+            return self.fallback.set_config(name, key, value);
+        };
+
+        let saved = unsafe {
+            (container.api().set_config_item.unwrap())(
+                container.0,
+                c_key.as_ptr(),
+                c_value.as_ptr(),
+            ) && (container.api().save_config.unwrap())(container.0, ptr::null())
+        };
+        if !saved {
+            return Err(Error::Runtime(format!(
+                "failed to set {} for {}",
+                key, name
+            )));
+        }
+        Ok(())
+    }
+
+    // The snapshot API is not mirrored in `ffi`, the tools do the same
+    fn snapshot(&mut self, name: &str) -> Result<String> {
+        self.fallback.snapshot(name)
+    }
+
+    fn restore(&mut self, name: &str, snapshot: &str, new_name: Option<&str>) -> Result<()> {
+        self.fallback.restore(name, snapshot, new_name)
+    }
+
+    fn destroy_snapshot(&mut self, name: &str, snapshot: &str) -> Result<()> {
+        self.fallback.destroy_snapshot(name, snapshot)
+    }
+
+    fn clone_container(&mut self, name: &str, new_name: &str) -> Result<()> {
+        self.fallback.clone_container(name, new_name)
+    }
+}
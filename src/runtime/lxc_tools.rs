@@ -0,0 +1,418 @@
+// Copyright 2024 David Araújo
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::Path;
+use std::process::ExitStatus;
+
+use super::Runtime;
+use crate::command::Cmd;
+use crate::context::Context;
+use crate::error::{Error, Result};
+use crate::{CreateArgs, DeleteArgs, ExecuteArgs, ListArgs, StartArgs, StopArgs};
+
+/// Runtime backed by the `lxc-*` command line tools.
+pub struct LxcTools {
+    ctx: Context,
+}
+
+impl LxcTools {
+    pub fn new(ctx: Context) -> LxcTools {
+        LxcTools { ctx }
+    }
+
+    // Starts an lxc tool command line with the global options
+    fn lxc(&self, program: &str) -> Cmd {
+        Cmd::new(program).args(self.ctx.lxc_args())
+    }
+}
+
+impl Runtime for LxcTools {
+    fn context(&self) -> &Context {
+        &self.ctx
+    }
+
+    fn create(&mut self, args: &CreateArgs) -> Result<()> {
+        let mut create_options: Vec<String> = Vec::new();
+        if let Some(config) = args.config.as_deref().filter(|config| !config.is_empty()) {
+            create_options.push(format!("--config={}", config));
+        }
+
+        if let Some(dir) = args.dir.as_deref().filter(|dir| !dir.is_empty()) {
+            self.ctx.create_dir_all(Path::new(dir))?;
+            create_options.push(format!("--dir={}", dir));
+        }
+
+        if let Some(network) = args
+            .network
+            .as_deref()
+            .filter(|network| !network.is_empty())
+        {
+            create_options.push(format!("--network={}", network));
+        }
+
+        // Parse template
+        let image: Vec<&str> = args.image.split(':').collect();
+        if image.len() != 3 {
+            return Err(Error::Invalid(format!(
+                "image `{}` is not DISTRO:RELEASE:ARCH",
+                args.image
+            )));
+        }
+
+        let cmd = self
+            .lxc("lxc-create")
+            .arg(format!("--name={}", args.name))
+            .args(create_options)
+            .args(["--template=download", "--"])
+            .arg(format!("--dist={}", image[0]))
+            .arg(format!("--release={}", image[1]))
+            .arg(format!("--arch={}", image[2]));
+
+        self.ctx.run(&cmd)
+    }
+
+    fn destroy(&mut self, args: &DeleteArgs) -> Result<()> {
+        let mut delete_options: Vec<String> = Vec::new();
+
+        if args.force {
+            delete_options.push("--force".to_string());
+        }
+
+        if args.snapshots {
+            delete_options.push("--snapshots".to_string());
+        }
+
+        if let Some(rcfile) = &args.rcfile {
+            delete_options.push(format!("--rcfile={}", rcfile));
+        }
+
+        let cmd = self
+            .lxc("lxc-destroy")
+            .arg(format!("--name={}", args.name))
+            .args(delete_options);
+
+        self.ctx.run(&cmd)
+    }
+
+    fn start(&mut self, args: &StartArgs) -> Result<()> {
+        let mut start_options: Vec<String> = Vec::new();
+
+        if args.daemon {
+            start_options.push("--daemon".to_string());
+        }
+
+        if args.foreground {
+            start_options.push("--foreground".to_string());
+        }
+
+        if let Some(pidfile) = &args.pidfile {
+            start_options.push(format!("--pidfile={}", pidfile));
+        }
+
+        if let Some(rcfile) = &args.rcfile {
+            start_options.push(format!("--rcfile={}", rcfile));
+        }
+
+        if let Some(console) = &args.console {
+            start_options.push(format!("--console={}", console));
+        }
+
+        if let Some(console_log) = &args.console_log {
+            start_options.push(format!("--console-log={}", console_log));
+        }
+
+        if args.close_all_fds {
+            start_options.push("--close-all-fds".to_string());
+        }
+
+        if let Some(define) = &args.define {
+            start_options.push(format!("--define={}", define));
+        }
+
+        if let Some(share_net) = &args.share_net {
+            start_options.push(format!("--share-net={}", share_net));
+        }
+
+        if let Some(share_ipc) = &args.share_ipc {
+            start_options.push(format!("--share-ipc={}", share_ipc));
+        }
+
+        if let Some(share_uts) = &args.share_uts {
+            start_options.push(format!("--share-uts={}", share_uts));
+        }
+
+        if let Some(share_pid) = &args.share_pid {
+            start_options.push(format!("--share-pid={}", share_pid));
+        }
+
+        let cmd = self
+            .lxc("lxc-start")
+            .arg(format!("--name={}", args.name))
+            .args(start_options);
+
+        self.ctx.run(&cmd)
+    }
+
+    fn stop(&mut self, args: &StopArgs) -> Result<()> {
+        let mut stop_options: Vec<String> = Vec::new();
+
+        if args.reboot {
+            stop_options.push("--reboot".to_string());
+        }
+
+        if args.nowait {
+            stop_options.push("--nowait".to_string());
+        }
+
+        if let Some(timeout) = &args.timeout {
+            stop_options.push(format!("--timeout={}", timeout));
+        }
+
+        if args.kill {
+            stop_options.push("--kill".to_string());
+        }
+
+        if args.nolock {
+            stop_options.push("--nolock".to_string());
+        }
+
+        if args.nokill {
+            stop_options.push("--nokill".to_string());
+        }
+
+        if let Some(rcfile) = &args.rcfile {
+            stop_options.push(format!("--rcfile={}", rcfile));
+        }
+
+        let cmd = self
+            .lxc("lxc-stop")
+            .arg(format!("--name={}", args.name))
+            .args(stop_options);
+
+        self.ctx.run(&cmd)
+    }
+
+    fn attach(&mut self, args: &ExecuteArgs) -> Result<ExitStatus> {
+        let mut execute_options: Vec<String> = Vec::new();
+
+        if let Some(elevated_privileges) = &args.elevated_privileges {
+            execute_options.push(format!("--elevated-privileges={}", elevated_privileges));
+        }
+
+        if let Some(arch) = &args.arch {
+            execute_options.push(format!("--arch={}", arch));
+        }
+
+        if let Some(namespaces) = &args.namespaces {
+            execute_options.push(format!("--namespaces={}", namespaces));
+        }
+
+        if let Some(remount_sys_proc) = &args.remount_sys_proc {
+            execute_options.push(format!("--remount-sys-proc={}", remount_sys_proc));
+        }
+
+        if args.clear_env {
+            execute_options.push("--clear-env".to_string());
+        }
+
+        if args.keep_env {
+            execute_options.push("--keep-env".to_string());
+        }
+
+        if let Some(pty_log) = &args.pty_log {
+            execute_options.push(format!("--pty-log={}", pty_log));
+        }
+
+        for var in &args.set_var {
+            execute_options.push(format!("--set-var={}", var));
+        }
+
+        for var in &args.keep_var {
+            execute_options.push(format!("--keep-var={}", var));
+        }
+
+        if let Some(rcfile) = &args.rcfile {
+            execute_options.push(format!("--rcfile={}", rcfile));
+        }
+
+        if let Some(uid) = &args.uid {
+            execute_options.push(format!("--uid={}", uid));
+        }
+
+        if let Some(gid) = &args.gid {
+            execute_options.push(format!("--gid={}", gid));
+        }
+
+        if let Some(context) = &args.context {
+            execute_options.push(format!("--context={}", context));
+        }
+
+        let mut cmd = self
+            .lxc("lxc-attach")
+            .arg(format!("--name={}", args.name))
+            .args(execute_options);
+
+        // Without a command lxc-attach spawns the container's shell
+        if !args.command.is_empty() {
+            cmd = cmd.arg("--").args(&args.command);
+        }
+
+        self.ctx.status(&cmd)
+    }
+
+    fn info(&mut self, name: &str, key: Option<&str>) -> Result<String> {
+        let mut cmd = self.lxc("lxc-info").arg(format!("--name={}", name));
+        if let Some(key) = key {
+            cmd = cmd.arg(format!("--config={}", key));
+        }
+
+        let output = cmd.capture(&self.ctx.output)?;
+
+        // A single key is printed as `key = value`
+        Ok(match key {
+            Some(_) => output
+                .split_once('=')
+                .map_or(output.as_str(), |(_, value)| value)
+                .trim()
+                .to_string(),
+            None => output,
+        })
+    }
+
+    fn cgroup(&mut self, name: &str, key: &str, value: Option<&str>) -> Result<String> {
+        let cmd = self
+            .lxc("lxc-cgroup")
+            .arg(format!("--name={}", name))
+            .arg(key);
+        match value {
+            Some(value) => {
+                self.ctx.run(&cmd.arg(value))?;
+                Ok(String::new())
+            }
+            None => cmd.capture(&self.ctx.output),
+        }
+    }
+
+    fn list(&mut self, args: &ListArgs) -> Result<String> {
+        let mut list_options: Vec<String> = Vec::new();
+
+        if args.line {
+            list_options.push("--line".to_string());
+        }
+
+        if args.fancy {
+            list_options.push("--fancy".to_string());
+        }
+
+        if let Some(fancy_format) = &args.fancy_format {
+            list_options.push(format!("--fancy-format={}", fancy_format.join(",")));
+        }
+
+        if args.active {
+            list_options.push("--active".to_string());
+        }
+
+        if args.running {
+            list_options.push("--running".to_string());
+        }
+
+        if args.frozen {
+            list_options.push("--frozen".to_string());
+        }
+
+        if args.stopped {
+            list_options.push("--stopped".to_string());
+        }
+
+        if args.defined {
+            list_options.push("--defined".to_string());
+        }
+
+        if let Some(nesting) = &args.nesting {
+            list_options.push(format!("--nesting={}", nesting));
+        }
+
+        if let Some(filter) = &args.filter {
+            list_options.push(format!("--filter={}", filter));
+        }
+
+        if let Some(groups) = &args.groups {
+            list_options.push(format!("--groups={}", groups.join(",")));
+        }
+
+        let cmd = self.lxc("lxc-ls").args(list_options);
+
+        cmd.capture(&self.ctx.output)
+    }
+
+    fn set_config(&mut self, name: &str, key: &str, value: &str) -> Result<()> {
+        let path = self.ctx.config_file(name);
+        self.ctx.append_line(&path, &format!("{} = {}", key, value))
+    }
+
+    fn snapshot(&mut self, name: &str) -> Result<String> {
+        let cmd = self.lxc("lxc-snapshot").arg(format!("--name={}", name));
+        let list = cmd.clone().arg("--list");
+        let before = snapshots(&list.capture(&self.ctx.output)?);
+        self.ctx.run(&cmd)?;
+
+        // lxc-snapshot only logs the name it picked, so it is the one that
+        // was not there before
+        snapshots(&list.capture(&self.ctx.output)?)
+            .into_iter()
+            .find(|snapshot| !before.contains(snapshot))
+            .ok_or_else(|| Error::Runtime(format!("no snapshot of {} was taken", name)))
+    }
+
+    fn restore(&mut self, name: &str, snapshot: &str, new_name: Option<&str>) -> Result<()> {
+        let mut cmd = self
+            .lxc("lxc-snapshot")
+            .arg(format!("--name={}", name))
+            .arg(format!("--restore={}", snapshot));
+        if let Some(new_name) = new_name {
+            cmd = cmd.arg(format!("--newname={}", new_name));
+        }
+        self.ctx.run(&cmd)
+    }
+
+    fn destroy_snapshot(&mut self, name: &str, snapshot: &str) -> Result<()> {
+        let cmd = self
+            .lxc("lxc-snapshot")
+            .arg(format!("--name={}", name))
+            .arg(format!("--destroy={}", snapshot));
+        self.ctx.run(&cmd)
+    }
+
+    fn clone_container(&mut self, name: &str, new_name: &str) -> Result<()> {
+        let cmd = self
+            .lxc("lxc-copy")
+            .arg(format!("--name={}", name))
+            .arg(format!("--newname={}", new_name));
+        self.ctx.run(&cmd)
+    }
+}
+
+// The snapshot names in the output of `lxc-snapshot --list`, whose lines
+// start with them
+fn snapshots(list: &str) -> Vec<String> {
+    list.lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|snapshot| {
+            snapshot
+                .strip_prefix("snap")
+                .is_some_and(|n| n.parse::<u32>().is_ok())
+        })
+        .map(str::to_string)
+        .collect()
+}
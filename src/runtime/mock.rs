@@ -0,0 +1,364 @@
+// Copyright 2024 David Araújo
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+
+use super::Runtime;
+use crate::context::Context;
+use crate::error::{Error, Result};
+use crate::{CreateArgs, DeleteArgs, ExecuteArgs, ListArgs, StartArgs, StopArgs};
+
+/// A runtime call, as recorded by `MockRuntime`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Call {
+    Create(String),
+    Destroy(String),
+    Start(String),
+    Stop(String),
+    Attach(String, Vec<String>),
+    Info(String, Option<String>),
+    Cgroup(String, String, Option<String>),
+    List,
+    SetConfig(String, String, String),
+    Snapshot(String),
+    Restore(String, String, Option<String>),
+    DestroySnapshot(String, String),
+    Clone(String, String),
+}
+
+#[derive(Debug, Default)]
+pub struct MockContainer {
+    pub running: bool,
+    pub rootfs: PathBuf,
+    pub config: Vec<(String, String)>,
+    pub cgroup: BTreeMap<String, String>,
+    /// Configuration of each snapshot, whose rootfs is kept on disk
+    pub snapshots: BTreeMap<String, Vec<(String, String)>>,
+}
+
+/// In-memory runtime that records every call and simulates container
+/// state. Each container gets a real rootfs directory under the context's
+/// lxcpath, so host-side steps such as copies can be checked on disk.
+pub struct MockRuntime {
+    ctx: Context,
+    pub calls: Vec<Call>,
+    pub containers: BTreeMap<String, MockContainer>,
+    /// Exit code reported for every attached command.
+    pub attach_code: i32,
+    /// `--rcfile` of every start, stop and destroy.
+    pub rcfiles: Vec<Option<String>>,
+    /// `--uid` and `--gid` of every attached command.
+    pub attached_as: Vec<(Option<String>, Option<String>)>,
+}
+
+impl MockRuntime {
+    pub fn new(ctx: Context) -> MockRuntime {
+        MockRuntime {
+            ctx,
+            calls: Vec::new(),
+            containers: BTreeMap::new(),
+            attach_code: 0,
+            rcfiles: Vec::new(),
+            attached_as: Vec::new(),
+        }
+    }
+
+    pub fn container(&self, name: &str) -> &MockContainer {
+        &self.containers[name]
+    }
+
+    fn existing(&mut self, name: &str) -> Result<&mut MockContainer> {
+        self.containers
+            .get_mut(name)
+            .ok_or_else(|| Error::Runtime(format!("container {} does not exist", name)))
+    }
+
+    fn stopped(&mut self, name: &str) -> Result<&mut MockContainer> {
+        let container = self.existing(name)?;
+        if container.running {
+            return Err(Error::Runtime(format!("{} is running", name)));
+        }
+        Ok(container)
+    }
+
+    fn snapshot_rootfs(&self, name: &str, snapshot: &str) -> PathBuf {
+        self.ctx
+            .container_dir(name)
+            .join("snaps")
+            .join(snapshot)
+            .join("rootfs")
+    }
+}
+
+// Copies a directory tree, like the dir backend of lxc-snapshot
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    std::fs::create_dir_all(to).map_err(Error::io(to))?;
+    for entry in std::fs::read_dir(from).map_err(Error::io(from))? {
+        let entry = entry.map_err(Error::io(from))?;
+        let (from, to) = (entry.path(), to.join(entry.file_name()));
+        if from.is_dir() {
+            copy_dir(&from, &to)?;
+        } else {
+            std::fs::copy(&from, &to).map_err(Error::io(&from))?;
+        }
+    }
+    Ok(())
+}
+
+impl Runtime for MockRuntime {
+    fn context(&self) -> &Context {
+        &self.ctx
+    }
+
+    fn create(&mut self, args: &CreateArgs) -> Result<()> {
+        self.calls.push(Call::Create(args.name.clone()));
+        if self.containers.contains_key(&args.name) {
+            return Err(Error::Runtime(format!(
+                "container {} already exists",
+                args.name
+            )));
+        }
+
+        let rootfs = match args.dir.as_deref().filter(|dir| !dir.is_empty()) {
+            Some(dir) => PathBuf::from(dir),
+            None => self.ctx.container_dir(&args.name).join("rootfs"),
+        };
+        std::fs::create_dir_all(&rootfs).map_err(Error::io(&rootfs))?;
+        self.containers.insert(
+            args.name.clone(),
+            MockContainer {
+                rootfs,
+                ..Default::default()
+            },
+        );
+        Ok(())
+    }
+
+    fn destroy(&mut self, args: &DeleteArgs) -> Result<()> {
+        self.calls.push(Call::Destroy(args.name.clone()));
+        self.rcfiles.push(args.rcfile.clone());
+        let container = self.existing(&args.name)?;
+        if container.running && !args.force {
+            return Err(Error::Runtime(format!("{} is running", args.name)));
+        }
+        if !container.snapshots.is_empty() && !args.snapshots {
+            return Err(Error::Runtime(format!("{} has snapshots", args.name)));
+        }
+        if let Some(container) = self.containers.remove(&args.name) {
+            std::fs::remove_dir_all(&container.rootfs).map_err(Error::io(&container.rootfs))?;
+        }
+        let snaps = self.ctx.container_dir(&args.name).join("snaps");
+        if snaps.exists() {
+            std::fs::remove_dir_all(&snaps).map_err(Error::io(&snaps))?;
+        }
+        Ok(())
+    }
+
+    fn start(&mut self, args: &StartArgs) -> Result<()> {
+        self.calls.push(Call::Start(args.name.clone()));
+        self.rcfiles.push(args.rcfile.clone());
+        self.existing(&args.name)?.running = true;
+        Ok(())
+    }
+
+    fn stop(&mut self, args: &StopArgs) -> Result<()> {
+        self.calls.push(Call::Stop(args.name.clone()));
+        self.rcfiles.push(args.rcfile.clone());
+        self.existing(&args.name)?.running = false;
+        Ok(())
+    }
+
+    fn attach(&mut self, args: &ExecuteArgs) -> Result<ExitStatus> {
+        self.calls
+            .push(Call::Attach(args.name.clone(), args.command.clone()));
+        self.attached_as.push((args.uid.clone(), args.gid.clone()));
+        if !self.existing(&args.name)?.running {
+            return Err(Error::Runtime(format!("{} is not running", args.name)));
+        }
+        Ok(ExitStatus::from_raw(self.attach_code << 8))
+    }
+
+    fn info(&mut self, name: &str, key: Option<&str>) -> Result<String> {
+        self.calls
+            .push(Call::Info(name.to_string(), key.map(str::to_string)));
+
+        let container = self.existing(name)?;
+        Ok(match key {
+            Some("lxc.rootfs.path") => format!("dir:{}", container.rootfs.display()),
+            Some(key) => container
+                .config
+                .iter()
+                .filter(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+                .collect::<Vec<_>>()
+                .join("\n"),
+            None => format!(
+                "Name: {}\nState: {}\n",
+                name,
+                if container.running {
+                    "RUNNING"
+                } else {
+                    "STOPPED"
+                }
+            ),
+        })
+    }
+
+    fn cgroup(&mut self, name: &str, key: &str, value: Option<&str>) -> Result<String> {
+        self.calls.push(Call::Cgroup(
+            name.to_string(),
+            key.to_string(),
+            value.map(str::to_string),
+        ));
+
+        let container = self.existing(name)?;
+        if !container.running {
+            return Err(Error::Runtime(format!("{} is not running", name)));
+        }
+        Ok(match value {
+            Some(value) => {
+                container.cgroup.insert(key.to_string(), value.to_string());
+                String::new()
+            }
+            None => container.cgroup.get(key).cloned().unwrap_or_default(),
+        })
+    }
+
+    fn list(&mut self, _args: &ListArgs) -> Result<String> {
+        self.calls.push(Call::List);
+        Ok(self
+            .containers
+            .keys()
+            .map(|name| format!("{}\n", name))
+            .collect())
+    }
+
+    fn set_config(&mut self, name: &str, key: &str, value: &str) -> Result<()> {
+        self.calls.push(Call::SetConfig(
+            name.to_string(),
+            key.to_string(),
+            value.to_string(),
+        ));
+        self.existing(name)?
+            .config
+            .push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    fn snapshot(&mut self, name: &str) -> Result<String> {
+        self.calls.push(Call::Snapshot(name.to_string()));
+        let container = self.stopped(name)?;
+        let snapshot = (0..)
+            .map(|i| format!("snap{}", i))
+            .find(|snapshot| !container.snapshots.contains_key(snapshot))
+            .unwrap();
+        let (rootfs, config) = (container.rootfs.clone(), container.config.clone());
+        copy_dir(&rootfs, &self.snapshot_rootfs(name, &snapshot))?;
+        self.existing(name)?
+            .snapshots
+            .insert(snapshot.clone(), config);
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, name: &str, snapshot: &str, new_name: Option<&str>) -> Result<()> {
+        self.calls.push(Call::Restore(
+            name.to_string(),
+            snapshot.to_string(),
+            new_name.map(str::to_string),
+        ));
+        let Some(config) = self.stopped(name)?.snapshots.get(snapshot).cloned() else {
+            return Err(Error::Runtime(format!(
+                "{} has no snapshot {}",
+                name, snapshot
+            )));
+        };
+        let source = self.snapshot_rootfs(name, snapshot);
+
+        match new_name {
+            Some(new_name) => {
+                if self.containers.contains_key(new_name) {
+                    return Err(Error::Runtime(format!(
+                        "container {} already exists",
+                        new_name
+                    )));
+                }
+                let rootfs = self.ctx.container_dir(new_name).join("rootfs");
+                copy_dir(&source, &rootfs)?;
+                self.containers.insert(
+                    new_name.to_string(),
+                    MockContainer {
+                        rootfs,
+                        config,
+                        ..Default::default()
+                    },
+                );
+            }
+            None => {
+                let container = self.existing(name)?;
+                let rootfs = container.rootfs.clone();
+                container.config = config;
+                std::fs::remove_dir_all(&rootfs).map_err(Error::io(&rootfs))?;
+                copy_dir(&source, &rootfs)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn destroy_snapshot(&mut self, name: &str, snapshot: &str) -> Result<()> {
+        self.calls.push(Call::DestroySnapshot(
+            name.to_string(),
+            snapshot.to_string(),
+        ));
+        if self.existing(name)?.snapshots.remove(snapshot).is_none() {
+            return Err(Error::Runtime(format!(
+                "{} has no snapshot {}",
+                name, snapshot
+            )));
+        }
+        let rootfs = self.snapshot_rootfs(name, snapshot);
+        std::fs::remove_dir_all(&rootfs).map_err(Error::io(&rootfs))
+    }
+
+    fn clone_container(&mut self, name: &str, new_name: &str) -> Result<()> {
+        self.calls
+            .push(Call::Clone(name.to_string(), new_name.to_string()));
+        let container = self.stopped(name)?;
+        let (source, mut config) = (container.rootfs.clone(), container.config.clone());
+        if self.containers.contains_key(new_name) {
+            return Err(Error::Runtime(format!(
+                "container {} already exists",
+                new_name
+            )));
+        }
+        for (key, value) in &mut config {
+            if key == "lxc.uts.name" {
+                *value = new_name.to_string();
+            }
+        }
+        let rootfs = self.ctx.container_dir(new_name).join("rootfs");
+        copy_dir(&source, &rootfs)?;
+        self.containers.insert(
+            new_name.to_string(),
+            MockContainer {
+                rootfs,
+                config,
+                ..Default::default()
+            },
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright 2024 David Araújo
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#[cfg(feature = "liblxc")]
+mod liblxc;
+mod lxc_tools;
+#[cfg(test)]
+pub mod mock;
+
+#[cfg(feature = "liblxc")]
+pub use liblxc::LibLxc;
+pub use lxc_tools::LxcTools;
+
+use std::process::ExitStatus;
+
+use crate::context::Context;
+use crate::error::Result;
+use crate::inspect::ContainerInfo;
+use crate::{CreateArgs, DeleteArgs, ExecuteArgs, ListArgs, StartArgs, StopArgs};
+
+/// Container operations needed by the `manage` layer. `LxcTools` drives the
+/// `lxc-*` command line tools, `LibLxc` (feature `liblxc`) calls liblxc
+/// directly and `mock::MockRuntime` records calls in memory.
+pub trait Runtime {
+    /// Global options this runtime was set up with.
+    fn context(&self) -> &Context;
+
+    fn create(&mut self, args: &CreateArgs) -> Result<()>;
+
+    fn destroy(&mut self, args: &DeleteArgs) -> Result<()>;
+
+    fn start(&mut self, args: &StartArgs) -> Result<()>;
+
+    fn stop(&mut self, args: &StopArgs) -> Result<()>;
+
+    /// Runs `args.command` inside the container, or its shell when empty,
+    /// and returns how it exited.
+    fn attach(&mut self, args: &ExecuteArgs) -> Result<ExitStatus>;
+
+    /// Value of the configuration item `key`, or the container's status
+    /// report when no key is given.
+    fn info(&mut self, name: &str, key: Option<&str>) -> Result<String>;
+
+    /// Reads the cgroup item `key`, or sets it when a value is given.
+    fn cgroup(&mut self, name: &str, key: &str, value: Option<&str>) -> Result<String>;
+
+    fn list(&mut self, args: &ListArgs) -> Result<String>;
+
+    /// Adds `key = value` to the container's configuration file.
+    fn set_config(&mut self, name: &str, key: &str, value: &str) -> Result<()>;
+
+    /// Snapshots the stopped container and returns the snapshot's name.
+    fn snapshot(&mut self, name: &str) -> Result<String>;
+
+    /// Restores `snapshot` over the container, or as a new container
+    /// `new_name` when given.
+    fn restore(&mut self, name: &str, snapshot: &str, new_name: Option<&str>) -> Result<()>;
+
+    fn destroy_snapshot(&mut self, name: &str, snapshot: &str) -> Result<()>;
+
+    /// Copies the stopped container as a new container `new_name`, whose
+    /// hostname becomes its name.
+    fn clone_container(&mut self, name: &str, new_name: &str) -> Result<()>;
+
+    /// Typed status of the container, read from its `info` report and
+    /// its `lxc.group` and `lxc.start.auto` items.
+    fn inspect(&mut self, name: &str) -> Result<ContainerInfo> {
+        let mut info = ContainerInfo::from_report(name, &self.info(name, None)?);
+        info.groups = self
+            .info(name, Some("lxc.group"))?
+            .split([',', '\n'])
+            .map(str::trim)
+            .filter(|group| !group.is_empty())
+            .map(str::to_string)
+            .collect();
+        info.autostart = self.info(name, Some("lxc.start.auto"))?.trim() == "1";
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runtime<R: Runtime>() {}
+
+    // main drives LibLxc with the feature and LxcTools without it, and
+    // LibLxc hands LxcTools whatever liblxc has no call for
+    #[test]
+    fn both_backends_are_runtimes() {
+        runtime::<LxcTools>();
+        #[cfg(feature = "liblxc")]
+        runtime::<LibLxc>();
+    }
+}
@@ -0,0 +1,258 @@
+// Copyright 2024 David Araújo
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use clap::{Command, ValueEnum};
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+use crate::inspect::OutputFormat;
+use crate::lxcfile::line_column;
+use crate::output::ColorChoice;
+
+pub const SYSTEM_SETTINGS: &str = "/etc/cmt/config.toml";
+
+/// Defaults for command line options, read from `/etc/cmt/config.toml`,
+/// `~/.config/cmt/config.toml` and `CMT_*` environment variables.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    pub lxcpath: Option<String>,
+    pub logfile: Option<String>,
+    pub logpriority: Option<String>,
+    pub color: Option<ColorChoice>,
+    /// `cmt create --image`
+    pub image: Option<String>,
+    /// `cmt stop --timeout`
+    pub stop_timeout: Option<u64>,
+    /// `cmt exec --uid` and `--gid`
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// `--output` of `cmt list` and `cmt config`
+    pub output: Option<OutputFormat>,
+    /// `cmt build --file`
+    pub build_file: Option<String>,
+}
+
+impl Settings {
+    /// Merges every source, the environment over the user file over the
+    /// system file. The command line is applied over all of them by clap.
+    pub fn load() -> Result<Settings> {
+        let mut settings = Settings::default();
+        for path in [Some(PathBuf::from(SYSTEM_SETTINGS)), user_settings()]
+            .into_iter()
+            .flatten()
+        {
+            if path.exists() {
+                settings = Settings::read(&path)?.over(settings);
+            }
+        }
+        Ok(Settings::from_env(|key| std::env::var(key).ok())?.over(settings))
+    }
+
+    pub fn read(path: &Path) -> Result<Settings> {
+        let contents = std::fs::read_to_string(path).map_err(Error::io(path))?;
+        toml::from_str(&contents).map_err(|e: toml::de::Error| {
+            let offset = e.span().map(|span| span.start).unwrap_or(0);
+            let (line, column) = line_column(&contents, offset);
+            Error::Invalid(format!(
+                "{}:{}:{}: {}",
+                path.display(),
+                line,
+                column,
+                e.message()
+            ))
+        })
+    }
+
+    /// Reads `CMT_LXCPATH`, `CMT_IMAGE` and so on, one for each setting.
+    pub fn from_env(var: impl Fn(&str) -> Option<String>) -> Result<Settings> {
+        Ok(Settings {
+            lxcpath: var("CMT_LXCPATH"),
+            logfile: var("CMT_LOGFILE"),
+            logpriority: var("CMT_LOGPRIORITY"),
+            color: env_value(&var, "CMT_COLOR", |v| ColorChoice::from_str(v, true))?,
+            image: var("CMT_IMAGE"),
+            stop_timeout: env_value(&var, "CMT_STOP_TIMEOUT", u64::from_str)?,
+            uid: env_value(&var, "CMT_UID", u32::from_str)?,
+            gid: env_value(&var, "CMT_GID", u32::from_str)?,
+            output: env_value(&var, "CMT_OUTPUT", |v| OutputFormat::from_str(v, true))?,
+            build_file: var("CMT_BUILD_FILE"),
+        })
+    }
+
+    /// Fills whatever `self` leaves unset from `lower`.
+    pub fn over(self, lower: Settings) -> Settings {
+        Settings {
+            lxcpath: self.lxcpath.or(lower.lxcpath),
+            logfile: self.logfile.or(lower.logfile),
+            logpriority: self.logpriority.or(lower.logpriority),
+            color: self.color.or(lower.color),
+            image: self.image.or(lower.image),
+            stop_timeout: self.stop_timeout.or(lower.stop_timeout),
+            uid: self.uid.or(lower.uid),
+            gid: self.gid.or(lower.gid),
+            output: self.output.or(lower.output),
+            build_file: self.build_file.or(lower.build_file),
+        }
+    }
+
+    /// Each setting as `(subcommand, option, value)`, where no subcommand
+    /// means a global option.
+    pub fn defaults(&self) -> Vec<(Option<&'static str>, &'static str, String)> {
+        let mut defaults = Vec::new();
+        let mut add = |subcommand, option, value: Option<String>| {
+            if let Some(value) = value {
+                defaults.push((subcommand, option, value));
+            }
+        };
+
+        add(None, "lxcpath", self.lxcpath.clone());
+        add(None, "logfile", self.logfile.clone());
+        add(None, "logpriority", self.logpriority.clone());
+        add(None, "color", self.color.map(possible_value));
+        add(Some("create"), "image", self.image.clone());
+        add(
+            Some("stop"),
+            "timeout",
+            self.stop_timeout.map(|t| t.to_string()),
+        );
+        add(Some("execute"), "uid", self.uid.map(|uid| uid.to_string()));
+        add(Some("execute"), "gid", self.gid.map(|gid| gid.to_string()));
+        add(Some("list"), "output", self.output.map(possible_value));
+        add(Some("config"), "output", self.output.map(possible_value));
+        add(Some("build"), "file", self.build_file.clone());
+
+        defaults
+    }
+
+    /// Makes every setting the default of its command line option, so an
+    /// option given explicitly still wins and `--help` shows the value.
+    pub fn apply(&self, mut cli: Command) -> Command {
+        for (subcommand, option, value) in self.defaults() {
+            cli = match subcommand {
+                Some(subcommand) => cli.mut_subcommand(subcommand, |sub| {
+                    sub.mut_arg(option, |arg| arg.default_value(value))
+                }),
+                None => cli.mut_arg(option, |arg| arg.default_value(value)),
+            };
+        }
+        cli
+    }
+}
+
+// `$XDG_CONFIG_HOME/cmt/config.toml`, or `~/.config/cmt/config.toml`
+fn user_settings() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_home.join("cmt/config.toml"))
+}
+
+fn env_value<T, E: std::fmt::Display>(
+    var: &impl Fn(&str) -> Option<String>,
+    key: &str,
+    parse: impl Fn(&str) -> std::result::Result<T, E>,
+) -> Result<Option<T>> {
+    var(key)
+        .map(|value| parse(&value).map_err(|e| Error::Invalid(format!("{}={}: {}", key, value, e))))
+        .transpose()
+}
+
+fn possible_value(value: impl ValueEnum) -> String {
+    value
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CmtCli;
+    use clap::{CommandFactory, FromArgMatches};
+
+    #[test]
+    fn command_line_beats_env_beats_user_beats_system() {
+        let tmp = tempfile::tempdir().unwrap();
+        let system = tmp.path().join("system.toml");
+        let user = tmp.path().join("user.toml");
+        std::fs::write(
+            &system,
+            "image = \"debian:bookworm:amd64\"\nlxcpath = \"/srv/lxc\"\nstop_timeout = 30\n",
+        )
+        .unwrap();
+        std::fs::write(&user, "image = \"alpine:3.20:arm64\"\noutput = \"json\"\n").unwrap();
+
+        let env = |key: &str| (key == "CMT_LXCPATH").then(|| "/data/lxc".to_string());
+        let settings = Settings::from_env(env)
+            .unwrap()
+            .over(Settings::read(&user).unwrap())
+            .over(Settings::read(&system).unwrap());
+
+        assert_eq!(settings.image.as_deref(), Some("alpine:3.20:arm64"));
+        assert_eq!(settings.lxcpath.as_deref(), Some("/data/lxc"));
+        assert_eq!(settings.stop_timeout, Some(30));
+        assert_eq!(settings.output, Some(OutputFormat::Json));
+
+        let cli = settings.apply(CmtCli::command());
+        let matches = cli
+            .try_get_matches_from(["cmt", "--lxcpath=/tmp/lxc", "create", "web"])
+            .unwrap();
+        let cli = CmtCli::from_arg_matches(&matches).unwrap();
+        assert_eq!(cli.lxcpath.as_deref(), Some("/tmp/lxc"));
+        match cli.sub {
+            Some(crate::Subcommands::Create(args)) => assert_eq!(args.image, "alpine:3.20:arm64"),
+            other => panic!("parsed {:?}", other),
+        }
+    }
+
+    #[test]
+    fn every_setting_names_an_existing_option() {
+        let settings = Settings {
+            lxcpath: Some("/srv/lxc".to_string()),
+            logfile: Some("/tmp/cmt.log".to_string()),
+            logpriority: Some("DEBUG".to_string()),
+            color: Some(ColorChoice::Never),
+            image: Some("alpine:3.20:amd64".to_string()),
+            stop_timeout: Some(10),
+            uid: Some(1000),
+            gid: Some(1000),
+            output: Some(OutputFormat::Table),
+            build_file: Some("build.toml".to_string()),
+        };
+
+        // clap panics on an unknown option while building the command
+        settings.apply(CmtCli::command()).debug_assert();
+    }
+
+    #[test]
+    fn bad_values_are_reported_with_their_source() {
+        let env = |key: &str| (key == "CMT_OUTPUT").then(|| "xml".to_string());
+        let err = Settings::from_env(env).unwrap_err();
+        assert_eq!(err.exit_code(), 2);
+        assert!(err.to_string().starts_with("CMT_OUTPUT=xml: "));
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("config.toml");
+        std::fs::write(&path, "image = \"alpine:3.20:amd64\"\ntimeout = 5\n").unwrap();
+        let err = Settings::read(&path).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with(&format!("{}:2:1: unknown field `timeout`", path.display())));
+    }
+}